@@ -0,0 +1,104 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// How many of the most recently sent alerts can be acknowledged
+const MAX_TRACKED_ALERTS: usize = 1000;
+
+/// Reactions acknowledging an alert, without variation selectors
+pub const ACK_EMOJIS: &[&str] = &["\u{2705}", "\u{1f440}"];
+
+#[derive(Debug, Clone)]
+pub struct SentAlert {
+    /// The ID of the chat event or message the alert was sent as
+    pub event_id: String,
+
+    /// The room the alert was sent to, reactions in other rooms don't count
+    pub room_id: String,
+
+    pub title: String,
+
+    pub acked_by: Vec<String>,
+
+    /// The ID of the note listing who acknowledged the alert
+    pub note: Option<String>,
+}
+
+impl SentAlert {
+    fn is(&self, room_id: &str, event_id: &str) -> bool {
+        self.room_id == room_id && self.event_id == event_id
+    }
+}
+
+/// Recently sent alerts and who acknowledged them
+#[derive(Clone, Default)]
+pub struct Acks {
+    alerts: Arc<Mutex<VecDeque<SentAlert>>>,
+}
+
+impl Acks {
+    pub fn record_sent(&self, event_id: &str, room_id: &str, title: &str) {
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() >= MAX_TRACKED_ALERTS {
+            alerts.pop_front();
+        }
+        alerts.push_back(SentAlert {
+            event_id: event_id.to_string(),
+            room_id: room_id.to_string(),
+            title: title.to_string(),
+            acked_by: Vec::new(),
+            note: None,
+        });
+    }
+
+    /// Returns the updated alert if `user` has not acknowledged it before
+    pub fn ack(&self, room_id: &str, event_id: &str, user: &str) -> Option<SentAlert> {
+        let mut alerts = self.alerts.lock().unwrap();
+        let alert = alerts
+            .iter_mut()
+            .find(|alert| alert.is(room_id, event_id))?;
+        if alert.acked_by.iter().any(|v| v == user) {
+            return None;
+        }
+        alert.acked_by.push(user.to_string());
+        Some(alert.clone())
+    }
+
+    pub fn set_note(&self, room_id: &str, event_id: &str, note: &str) {
+        let mut alerts = self.alerts.lock().unwrap();
+        if let Some(alert) = alerts.iter_mut().find(|alert| alert.is(room_id, event_id)) {
+            alert.note = Some(note.to_string());
+        }
+    }
+
+    /// The number of acknowledged and of all tracked alerts
+    pub fn count(&self) -> (usize, usize) {
+        let alerts = self.alerts.lock().unwrap();
+        let acked = alerts
+            .iter()
+            .filter(|alert| !alert.acked_by.is_empty())
+            .count();
+        (acked, alerts.len())
+    }
+}
+
+pub fn is_ack_emoji(emoji: &str) -> bool {
+    ACK_EMOJIS.contains(&emoji.trim_end_matches('\u{fe0f}'))
+}
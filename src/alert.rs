@@ -22,14 +22,27 @@ pub mod message;
 pub mod util;
 
 use crate::config::ClientConfig;
+use crate::message::Attachment;
+use crate::message::Confirmation;
 use crate::message::Level;
 use crate::message::Message;
 use crate::message::Packet;
-use crate::message::Sas;
+use crate::message::Verification;
+use crate::message::VerifyCommand;
 
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::io::Write;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
 
 use chrono::Local;
 
@@ -54,22 +67,131 @@ fn main() {
 
     let config = config::parse_config::<ClientConfig>(config_path);
 
-    let packet = if let Some(sas) = arguments.value_of(alert_cli_parser::FLAG_VERIFY) {
-        compose_sas_verification(sas)
+    let command = if arguments.is_present(alert_cli_parser::FLAG_LIST_VERIFICATIONS) {
+        Some(VerifyCommand::List)
+    } else if let Some(id) = arguments.value_of(alert_cli_parser::FLAG_CANCEL_VERIFICATION) {
+        Some(VerifyCommand::Cancel(id.to_string()))
+    } else if arguments.is_present(alert_cli_parser::FLAG_VERIFY) {
+        Some(VerifyCommand::Show(
+            arguments
+                .value_of(alert_cli_parser::FLAG_VERIFY)
+                .map(str::to_string),
+        ))
     } else {
-        compose_message_from_arguments(arguments)
+        None
     };
+    if let Some(command) = command {
+        if let Err(e) = verify(&config.socket_path, command) {
+            error!("Verification failed: {}", e);
+        }
+        return;
+    }
+
+    let packet = compose_message_from_arguments(arguments, &config);
 
     send_message(&config.socket_path, packet);
 }
 
-fn compose_sas_verification(sas: &str) -> Packet {
-    Packet::Sas(Sas {
-        input: sas.to_string(),
-    })
+/// Run a verification command against the daemon
+fn verify(socket_path: &str, command: VerifyCommand) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    write_line(&mut stream, &Packet::Verify(command.clone()))?;
+
+    match command {
+        VerifyCommand::List => {
+            let verifications: Vec<Verification> = read_line(&mut reader)?;
+            if verifications.is_empty() {
+                println!("No pending verifications");
+            }
+            for verification in verifications {
+                let state = if verification.decimals.is_some() {
+                    "ready"
+                } else {
+                    "waiting for keys"
+                };
+                println!(
+                    "{}  device {} of {}, {}",
+                    verification.id, verification.device_id, verification.user_id, state
+                );
+            }
+        }
+        VerifyCommand::Cancel(id) => {
+            if read_line::<bool>(&mut reader)? {
+                println!("Cancelled verification {}", id);
+            } else {
+                println!("No pending verification {}", id);
+            }
+        }
+        VerifyCommand::Show(_) => confirm(&mut stream, &mut reader)?,
+    }
+
+    Ok(())
 }
 
-fn compose_message_from_arguments(args: clap::ArgMatches) -> Packet {
+/// Show the verification sent by the daemon and let the user confirm it
+fn confirm(stream: &mut UnixStream, reader: &mut BufReader<UnixStream>) -> std::io::Result<()> {
+    println!("Waiting for a verification, start it on the other device");
+
+    let verification: Verification = read_line(reader)?;
+
+    println!(
+        "Verification {} with device {} of {}",
+        verification.id, verification.device_id, verification.user_id
+    );
+    if !verification.emoji.is_empty() {
+        println!("Emoji:");
+        for emoji in &verification.emoji {
+            println!("  {}  {}", emoji.symbol, emoji.description);
+        }
+    }
+    if let Some((first, second, third)) = verification.decimals {
+        println!("Numbers: {} {} {}", first, second, third);
+    }
+
+    print!("Does the other device show the same? [y/n] ");
+    stdout().flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+
+    write_line(
+        stream,
+        &Confirmation {
+            id: verification.id,
+            confirmed,
+        },
+    )?;
+
+    if confirmed {
+        println!("Confirmed. Verification completes once the other device confirms as well");
+    } else {
+        println!("Verification cancelled");
+    }
+    Ok(())
+}
+
+fn read_line<T: serde::de::DeserializeOwned>(
+    reader: &mut BufReader<UnixStream>,
+) -> std::io::Result<T> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "daemon closed the connection, check its log",
+        ));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+fn write_line<T: serde::Serialize>(stream: &mut UnixStream, value: &T) -> std::io::Result<()> {
+    let mut raw = serde_json::to_string(value)?;
+    raw.push('\n');
+    stream.write_all(raw.as_bytes())
+}
+
+fn compose_message_from_arguments(args: clap::ArgMatches, config: &ClientConfig) -> Packet {
     Packet::Message(Message {
         title: args
             .value_of(alert_cli_parser::FLAG_TITLE)
@@ -100,9 +222,102 @@ fn compose_message_from_arguments(args: clap::ArgMatches) -> Packet {
         timestamp: Local::now(),
 
         fields: parse_additional_fields(args.values_of(alert_cli_parser::FLAG_FIELD)),
+
+        key: args
+            .value_of(alert_cli_parser::FLAG_KEY)
+            .map(str::to_string),
+
+        thread: args
+            .value_of(alert_cli_parser::FLAG_THREAD)
+            .map(str::to_string),
+
+        mentions: args
+            .values_of(alert_cli_parser::FLAG_MENTION)
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default(),
+
+        attachments: read_attachments(
+            args.values_of(alert_cli_parser::FLAG_ATTACH),
+            config.max_attachment_size,
+        ),
     })
 }
 
+fn read_attachments(values: Option<clap::Values>, max_size: usize) -> Vec<Attachment> {
+    let mut attachments = Vec::default();
+    if let Some(values) = values {
+        for item in values {
+            let path = Path::new(item);
+            let (content, truncated) = match read_tail(path, max_size) {
+                Err(e) => {
+                    warn!("Skipping attachment '{}': {}", item, e);
+                    continue;
+                }
+                Ok(v) => v,
+            };
+
+            if truncated {
+                warn!(
+                    "Attachment '{}' is larger than {} bytes, keeping only its end",
+                    item, max_size
+                );
+            }
+            attachments.push(Attachment {
+                name: path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| item.to_string()),
+                content_type: guess_content_type(path, &content).to_string(),
+                content,
+                truncated,
+            });
+        }
+    }
+    attachments
+}
+
+/// Read at most the last `max_size` bytes of the file, and whether anything
+/// was left out, without loading a large log as a whole. Pipes cannot seek,
+/// so they are read to their end.
+fn read_tail(path: &Path, max_size: usize) -> io::Result<(Vec<u8>, bool)> {
+    let mut file = File::open(path)?;
+    let mut content = Vec::new();
+
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        file.read_to_end(&mut content)?;
+        let skipped = content.len().saturating_sub(max_size);
+        content.drain(..skipped);
+        return Ok((content, skipped > 0));
+    }
+
+    let skipped = metadata.len().saturating_sub(max_size as u64);
+    file.seek(SeekFrom::Start(skipped))?;
+    file.take(max_size as u64).read_to_end(&mut content)?;
+    Ok((content, skipped > 0))
+}
+
+fn guess_content_type(path: &Path, content: &[u8]) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|v| v.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "json" => "application/json",
+        "gz" => "application/gzip",
+        "zip" => "application/zip",
+        _ if std::str::from_utf8(content).is_ok() => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
 fn parse_additional_fields(values: Option<clap::Values>) -> BTreeMap<String, String> {
     let mut fields = BTreeMap::default();
     if let Some(values) = values {
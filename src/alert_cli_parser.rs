@@ -27,7 +27,20 @@ pub const FLAG_TEXT: &str = "TEXT";
 pub const FLAG_CHANNEL: &str = "CHANNEL";
 pub const FLAG_LEVEL: &str = "LEVEL";
 pub const FLAG_FIELD: &str = "FIELD";
+pub const FLAG_ATTACH: &str = "ATTACH";
+pub const FLAG_KEY: &str = "KEY";
+pub const FLAG_THREAD: &str = "THREAD";
+pub const FLAG_MENTION: &str = "MENTION";
+
 pub const FLAG_VERIFY: &str = "VERIFY";
+pub const FLAG_LIST_VERIFICATIONS: &str = "LIST_VERIFICATIONS";
+pub const FLAG_CANCEL_VERIFICATION: &str = "CANCEL_VERIFICATION";
+
+const VERIFICATION_FLAGS: [&str; 3] = [
+    FLAG_VERIFY,
+    FLAG_LIST_VERIFICATIONS,
+    FLAG_CANCEL_VERIFICATION,
+];
 
 pub fn parse_arguments() -> clap::ArgMatches {
     App::new("alert")
@@ -43,22 +56,41 @@ pub fn parse_arguments() -> clap::ArgMatches {
             Arg::new(FLAG_TITLE)
                 .help("The title of the message")
                 .value_name("TITLE")
-                .required_unless_present(FLAG_VERIFY),
+                .required_unless_present_any(VERIFICATION_FLAGS),
         )
         .arg(
             Arg::new(FLAG_TEXT)
                 .help("The content of the message")
                 .value_name("TEXT")
-                .required_unless_present(FLAG_VERIFY),
+                .required_unless_present_any(VERIFICATION_FLAGS),
         )
         .arg(
             Arg::new(FLAG_VERIFY)
                 .short('V')
                 .long("verify")
-                .help("Verify this matrix device. E.g. 1234,1234,1234")
-                .value_name("SAS")
-                .conflicts_with(FLAG_TITLE)
-                .conflicts_with(FLAG_TEXT),
+                .value_name("ID")
+                .min_values(0)
+                .max_values(1)
+                .help("Interactively verify the Matrix device of alerter, answering the verification with this ID or the next one")
+                .conflicts_with_all(&[FLAG_TITLE, FLAG_TEXT]),
+        )
+        .arg(
+            Arg::new(FLAG_LIST_VERIFICATIONS)
+                .long("list-verifications")
+                .help("List the pending Matrix verifications")
+                .conflicts_with_all(&[FLAG_TITLE, FLAG_TEXT, FLAG_VERIFY]),
+        )
+        .arg(
+            Arg::new(FLAG_CANCEL_VERIFICATION)
+                .long("cancel-verification")
+                .value_name("ID")
+                .help("Cancel a pending Matrix verification")
+                .conflicts_with_all(&[
+                    FLAG_TITLE,
+                    FLAG_TEXT,
+                    FLAG_VERIFY,
+                    FLAG_LIST_VERIFICATIONS,
+                ]),
         )
         .arg(
             Arg::new(FLAG_CONFIG)
@@ -99,6 +131,36 @@ pub fn parse_arguments() -> clap::ArgMatches {
                 .help("More key-value pairs as key:value")
                 .multiple_occurrences(true),
         )
+        .arg(
+            Arg::new(FLAG_ATTACH)
+                .short('a')
+                .long("attach")
+                .value_name("PATH")
+                .help("A file to attach, e.g. a job log. Can be repeated")
+                .multiple_occurrences(true),
+        )
+        .arg(
+            Arg::new(FLAG_KEY)
+                .short('k')
+                .long("key")
+                .value_name("KEY")
+                .help("Update the earlier message sent with this key instead of sending a new one"),
+        )
+        .arg(
+            Arg::new(FLAG_THREAD)
+                .short('T')
+                .long("thread")
+                .value_name("KEY")
+                .help("Group the message into a daily thread with all others of this key"),
+        )
+        .arg(
+            Arg::new(FLAG_MENTION)
+                .short('m')
+                .long("mention")
+                .value_name("USER")
+                .help("A user to notify, e.g. @alice:example.org or U012AB3CD. Can be repeated")
+                .multiple_occurrences(true),
+        )
         .arg(
             Arg::new(FLAG_LOG_CONFIG)
                 .short('v')
@@ -0,0 +1,259 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::Alertmanager as AlertmanagerConfig;
+use crate::message::Level;
+use crate::message::Message;
+use crate::spool_dispatcher::Report;
+use crate::util;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::time::interval;
+
+use serde_derive::Serialize;
+
+use thiserror::Error;
+
+use url::Url;
+
+use log::debug;
+use log::warn;
+
+type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Serialize, Clone)]
+struct Alert {
+    labels: Labels,
+
+    annotations: BTreeMap<String, String>,
+
+    #[serde(rename = "startsAt")]
+    starts_at: String,
+
+    #[serde(rename = "endsAt", skip_serializing_if = "Option::is_none")]
+    ends_at: Option<String>,
+
+    #[serde(rename = "generatorURL", skip_serializing_if = "Option::is_none")]
+    generator_url: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to post to alertmanager: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("alertmanager replied with status {0}")]
+    StatusCode(u16),
+}
+
+pub struct Alertmanager {
+    config: AlertmanagerConfig,
+
+    inline_size: usize,
+
+    /// Alerts which have not been resolved yet by an OK message, keyed by
+    /// their labels without `level`
+    firing: BTreeMap<Labels, Alert>,
+
+    spooler: Receiver<Message>,
+
+    send_reporter: Sender<Report>,
+
+    terminator: tokio::sync::broadcast::Receiver<()>,
+}
+
+impl Alertmanager {
+    pub fn new(
+        spooler: Receiver<Message>,
+        send_reporter: Sender<Report>,
+        config: AlertmanagerConfig,
+        inline_size: usize,
+        terminator: tokio::sync::broadcast::Receiver<()>,
+    ) -> Self {
+        Alertmanager {
+            config,
+            inline_size,
+            firing: BTreeMap::new(),
+            spooler,
+            send_reporter,
+            terminator,
+        }
+    }
+
+    pub async fn send_messages(&mut self) {
+        let mut resend_ticker = interval(Duration::from_secs(self.config.resend_interval.max(1)));
+        resend_ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                next = self.spooler.recv() => {
+                    if let Some(message) = next {
+                        debug!("Sending message");
+                        if let Err(e) = self.send_message(&message).await {
+                            warn!("Error while sending: {}", e);
+                            if self.send_reporter.send(Report::Failed(message)).await.is_err() {
+                                debug!("Alertmanager shutting down because send_reporter is down after trying to report error");
+                                return;
+                            }
+                        } else if self.send_reporter.send(Report::Sent).await.is_err() {
+                            debug!("Alertmanager shutting down because send_reporter is down");
+                            return;
+                        }
+                    } else {
+                        debug!("Alertmanager shutting down because spooler is down");
+                        return;
+                    }
+                }
+                _ = resend_ticker.tick() => {
+                    self.resend_firing_alerts().await;
+                }
+                _ = self.terminator.recv() => {
+                    debug!("Alertmanager shutting down on termination signal");
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Alertmanager resolves alerts on its own if they are not repeated
+    /// within its `resolve_timeout`, so keep them alive until an OK message
+    /// arrives.
+    async fn resend_firing_alerts(&self) {
+        if self.firing.is_empty() {
+            return;
+        }
+
+        debug!("Resending {} firing alerts", self.firing.len());
+        let alerts = self.firing.values().cloned().collect::<Vec<_>>();
+        if let Err(e) = self.post(&alerts).await {
+            warn!("Error while resending firing alerts: {}", e);
+        }
+    }
+
+    async fn send_message(&mut self, message: &Message) -> Result<(), Error> {
+        let identity = self.identity(message);
+        let mut alerts = Vec::new();
+
+        // An alert at the same level is replaced by posting the new one with
+        // identical labels, resolving it as well would race with that
+        if let Some(mut previous) = self.firing.get(&identity).cloned() {
+            if previous.labels.get("level").map(String::as_str) != Some(message.level.name()) {
+                previous.ends_at = Some(message.timestamp.to_rfc3339());
+                alerts.push(previous);
+            }
+        } else if let Level::Ok = message.level {
+            // Nothing is known about alerts fired before a restart, so resolve
+            // whatever may still be firing at any level
+            let resolved = self.build_alert(message, identity.clone());
+            for level in [Level::Warn, Level::Error, Level::Unknown] {
+                let mut alert = resolved.clone();
+                alert
+                    .labels
+                    .insert("level".to_string(), level.name().to_string());
+                alert.ends_at = Some(message.timestamp.to_rfc3339());
+                alerts.push(alert);
+            }
+        }
+
+        let alert = if let Level::Ok = message.level {
+            None
+        } else {
+            Some(self.build_alert(message, identity.clone()))
+        };
+        alerts.extend(alert.clone());
+
+        self.post(&alerts).await?;
+
+        match alert {
+            Some(alert) => self.firing.insert(identity, alert),
+            None => self.firing.remove(&identity),
+        };
+
+        Ok(())
+    }
+
+    async fn post(&self, alerts: &[Alert]) -> Result<(), Error> {
+        let client = reqwest::Client::builder().build()?;
+
+        let mut builder = client
+            .post(format!(
+                "{}/api/v2/alerts",
+                self.config.url.trim_end_matches('/')
+            ))
+            .json(alerts);
+
+        if let Some(username) = &self.config.username {
+            builder = builder.basic_auth(username, self.config.password.as_ref());
+        }
+
+        let response = builder.send().await?;
+
+        match response.status().as_u16() {
+            200 => Ok(()),
+            code => {
+                warn!("Upstream reported error: {:#?}", response);
+                Err(Error::StatusCode(code))
+            }
+        }
+    }
+
+    fn build_alert(&self, message: &Message, mut labels: Labels) -> Alert {
+        labels.insert("level".to_string(), message.level.name().to_string());
+
+        let mut annotations = BTreeMap::new();
+        annotations.insert("summary".to_string(), message.title.to_string());
+        annotations.insert(
+            "description".to_string(),
+            message.inline_attachments(self.inline_size).text,
+        );
+        if let Some(link) = &message.link {
+            annotations.insert("link".to_string(), link.to_string());
+        }
+
+        Alert {
+            labels,
+            annotations,
+            starts_at: message.timestamp.to_rfc3339(),
+            ends_at: None,
+            generator_url: message
+                .link
+                .as_deref()
+                .map(Url::parse)
+                .and_then(Result::ok)
+                .map(String::from),
+        }
+    }
+
+    /// The labels identifying an alert across levels
+    fn identity(&self, message: &Message) -> Labels {
+        let mut labels = Labels::new();
+        labels.insert("alertname".to_string(), message.title.to_string());
+        labels.insert("host".to_string(), util::hostname());
+
+        for key in &self.config.labels {
+            if let Some(value) = message.fields.get(key) {
+                labels.insert(util::sanitise_label_name(key), value.to_string());
+            }
+        }
+
+        labels
+    }
+}
@@ -0,0 +1,254 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::acks::Acks;
+use crate::message::Message;
+use crate::spool_dispatcher::Control;
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use regex::Regex;
+
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+use log::info;
+use log::warn;
+
+const HELP: &str = "Available commands:
+!status: Show spooled messages, backoff, uptime, acknowledgements and silences
+!silence <pattern> <duration>: Drop messages whose title matches the regular expression <pattern> for <duration>, e.g. 30m, 2h or 1d
+!unsilence [<pattern>]: Remove the silence for <pattern> or all silences
+!flush: Retry all spooled messages now
+!help: Show this help";
+
+struct Silence {
+    pattern: Regex,
+
+    until: Instant,
+}
+
+/// Message title patterns which are currently not sent
+#[derive(Clone, Default)]
+pub struct Silences {
+    silences: Arc<Mutex<Vec<Silence>>>,
+}
+
+impl Silences {
+    pub fn is_silenced(&self, message: &Message) -> bool {
+        let mut silences = self.silences.lock().unwrap();
+        let now = Instant::now();
+        silences.retain(|silence| silence.until > now);
+        silences
+            .iter()
+            .any(|silence| silence.pattern.is_match(&message.title))
+    }
+
+    fn add(&self, pattern: Regex, until: Instant) {
+        let mut silences = self.silences.lock().unwrap();
+        silences.retain(|silence| silence.pattern.as_str() != pattern.as_str());
+        silences.push(Silence { pattern, until });
+    }
+
+    /// Remove the silences with `pattern` or all of them, returning how many
+    /// were removed
+    fn remove(&self, pattern: Option<&str>) -> usize {
+        let mut silences = self.silences.lock().unwrap();
+        let before = silences.len();
+        match pattern {
+            Some(pattern) => silences.retain(|silence| silence.pattern.as_str() != pattern),
+            None => silences.clear(),
+        }
+        before - silences.len()
+    }
+
+    fn describe(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.silences
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|silence| silence.until > now)
+            .map(|silence| {
+                format!(
+                    "'{}' for {}",
+                    silence.pattern,
+                    format_duration(silence.until - now)
+                )
+            })
+            .collect()
+    }
+}
+
+/// Executes chat commands of authorised users
+#[derive(Clone)]
+pub struct CommandHandler {
+    admins: Vec<String>,
+
+    silences: Silences,
+
+    acks: Acks,
+
+    control: Sender<Control>,
+
+    started: Instant,
+}
+
+impl CommandHandler {
+    pub fn new(
+        admins: Vec<String>,
+        silences: Silences,
+        acks: Acks,
+        control: Sender<Control>,
+    ) -> Self {
+        CommandHandler {
+            admins,
+            silences,
+            acks,
+            control,
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the reply to `body` if it is a command of an authorised user
+    pub async fn handle(&self, sender: &str, body: &str) -> Option<String> {
+        let mut words = body.split_whitespace();
+        let command = words.next().filter(|word| word.starts_with('!'))?;
+
+        if !self.admins.iter().any(|admin| admin == sender) {
+            warn!(
+                "Ignoring command {} of unauthorised user {}",
+                command, sender
+            );
+            return None;
+        }
+
+        info!("Executing command {} of {}", command, sender);
+        let reply = match command {
+            "!status" => self.status().await,
+            "!silence" => self.silence(words.next(), words.next()),
+            "!unsilence" => self.unsilence(words.next()),
+            "!flush" => self.flush().await,
+            "!help" => HELP.to_string(),
+            _ => format!("Unknown command {}, see !help", command),
+        };
+
+        Some(reply)
+    }
+
+    async fn status(&self) -> String {
+        let mut lines = vec![format!(
+            "Up for {}",
+            format_duration(self.started.elapsed())
+        )];
+
+        let (reply, status) = oneshot::channel();
+        match self.control.send(Control::Status(reply)).await {
+            Ok(()) => match status.await {
+                Ok(status) => lines.push(format!(
+                    "{} messages spooled, retrying after {}",
+                    status.queued,
+                    format_duration(status.backoff)
+                )),
+                Err(_) => lines.push("Spool status is unavailable".to_string()),
+            },
+            Err(_) => lines.push("Spool status is unavailable".to_string()),
+        }
+
+        let (acked, sent) = self.acks.count();
+        lines.push(format!(
+            "{} of the last {} alerts acknowledged",
+            acked, sent
+        ));
+
+        let silences = self.silences.describe();
+        if silences.is_empty() {
+            lines.push("No silences".to_string());
+        } else {
+            lines.push(format!("Silenced: {}", silences.join(", ")));
+        }
+
+        lines.join("\n")
+    }
+
+    fn silence(&self, pattern: Option<&str>, duration: Option<&str>) -> String {
+        let (pattern, duration) = match (pattern, duration.and_then(parse_duration)) {
+            (Some(pattern), Some(duration)) => (pattern, duration),
+            _ => return "Usage: !silence <pattern> <duration>".to_string(),
+        };
+
+        let pattern = match Regex::new(pattern) {
+            Err(e) => return format!("Invalid pattern: {}", e),
+            Ok(v) => v,
+        };
+
+        let until = match Instant::now().checked_add(duration) {
+            None => return "Duration is too long".to_string(),
+            Some(v) => v,
+        };
+
+        let reply = format!("Silenced '{}' for {}", pattern, format_duration(duration));
+        self.silences.add(pattern, until);
+        reply
+    }
+
+    fn unsilence(&self, pattern: Option<&str>) -> String {
+        match self.silences.remove(pattern) {
+            0 => "No matching silence".to_string(),
+            1 => "Removed 1 silence".to_string(),
+            n => format!("Removed {} silences", n),
+        }
+    }
+
+    async fn flush(&self) -> String {
+        match self.control.send(Control::Flush).await {
+            Ok(()) => "Retrying all spooled messages now".to_string(),
+            Err(_) => "Spool is unavailable".to_string(),
+        }
+    }
+}
+
+/// Parse durations like `90s`, `30m`, `2h` or `1d`
+fn parse_duration(raw: &str) -> Option<Duration> {
+    if !raw.is_ascii() {
+        return None;
+    }
+    let split = raw.len().checked_sub(1)?;
+    let (amount, unit) = raw.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount.checked_mul(factor)?))
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        3600..=86399 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
@@ -15,12 +15,16 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::config::Backend;
+use crate::alertmanager::Alertmanager;
+use crate::config::Backend as BackendConfig;
 use crate::config::Config;
-use crate::config::Matrix as MatrixConfig;
 use crate::config::Slack as SlackConfig;
+use crate::config::SlackApi as SlackApiConfig;
+use crate::key_store::KeyStore;
 use crate::listener::Listener;
+use crate::loki::Loki;
 use crate::matrix::Matrix;
+use crate::slack::Endpoint;
 use crate::slack::Slack;
 use crate::spool_dispatcher::SpoolDispatcher;
 use crate::spooler::Spooler;
@@ -29,14 +33,22 @@ use tokio::sync::broadcast::Sender;
 
 use log::error;
 
+enum Backend {
+    Slack(Slack),
+
+    Matrix(Matrix),
+
+    Loki(Loki),
+
+    Alertmanager(Alertmanager),
+}
+
 pub struct Daemon {
     listener: Listener,
 
     spool_dispatcher: SpoolDispatcher,
 
-    slack: Option<Slack>,
-
-    matrix: Option<Matrix>,
+    backend: Backend,
 
     terminator: Sender<()>,
 }
@@ -47,6 +59,7 @@ impl Daemon {
         let (to_spooler, spooler_receiver) = tokio::sync::mpsc::channel(5);
         let (terminator, terminatee) = tokio::sync::broadcast::channel(1);
         let (to_verifier, verifier_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (to_control, control_receiver) = tokio::sync::mpsc::channel(5);
 
         let spooler = Spooler::new(&config.spool_path);
 
@@ -54,6 +67,7 @@ impl Daemon {
             spooler,
             to_matrix.clone(),
             spooler_receiver,
+            control_receiver,
             terminator.subscribe(),
         );
 
@@ -61,29 +75,72 @@ impl Daemon {
             &config.socket_path,
             to_matrix,
             to_verifier,
+            config.attachments.max_size,
             terminator.subscribe(),
         );
 
-        let (slack, matrix) = match config.backend {
-            Backend::Slack(SlackConfig { webhook }) => {
-                let slack = Slack::new(matrix_receiver, to_spooler, webhook, terminatee);
+        let key_store_path = config.key_store_path();
 
-                (Some(slack), None)
+        let backend = match config.backend {
+            BackendConfig::Slack(SlackConfig {
+                webhook,
+                message_template,
+                rate_limit,
+                mentions,
+            }) => {
+                let slack = match Slack::new(
+                    matrix_receiver,
+                    to_spooler,
+                    Endpoint::Webhook(webhook),
+                    message_template.as_deref(),
+                    &rate_limit,
+                    config.attachments.inline_size,
+                    terminatee,
+                ) {
+                    Err(e) => {
+                        error!("{}", e);
+                        return None;
+                    }
+                    Ok(v) => v.with_mentions(mentions),
+                };
+
+                Backend::Slack(slack)
             }
-            Backend::Matrix(MatrixConfig {
-                user,
-                password,
-                room,
+            BackendConfig::SlackApi(SlackApiConfig {
+                token,
+                channel,
                 message_template,
+                rate_limit,
+                mentions,
             }) => {
+                let slack = match Slack::new(
+                    matrix_receiver,
+                    to_spooler,
+                    Endpoint::Api { token, channel },
+                    message_template.as_deref(),
+                    &rate_limit,
+                    config.attachments.inline_size,
+                    terminatee,
+                ) {
+                    Err(e) => {
+                        error!("{}", e);
+                        return None;
+                    }
+                    Ok(v) => v
+                        .with_key_store(KeyStore::load(&key_store_path))
+                        .with_mentions(mentions),
+                };
+
+                Backend::Slack(slack)
+            }
+            BackendConfig::Matrix(matrix_config) => {
                 let matrix = match Matrix::new(
-                    &user,
-                    &password,
-                    &room,
-                    &message_template,
+                    &matrix_config,
                     matrix_receiver,
                     to_spooler,
                     verifier_receiver,
+                    to_control,
+                    KeyStore::load(&key_store_path),
                 ) {
                     Err(e) => {
                         error!("{}", e);
@@ -92,15 +149,30 @@ impl Daemon {
                     Ok(v) => v,
                 };
 
-                (None, Some(matrix))
+                Backend::Matrix(matrix)
+            }
+            BackendConfig::Loki(loki_config) => Backend::Loki(Loki::new(
+                matrix_receiver,
+                to_spooler,
+                loki_config,
+                config.attachments.inline_size,
+                terminatee,
+            )),
+            BackendConfig::Alertmanager(alertmanager_config) => {
+                Backend::Alertmanager(Alertmanager::new(
+                    matrix_receiver,
+                    to_spooler,
+                    alertmanager_config,
+                    config.attachments.inline_size,
+                    terminatee,
+                ))
             }
         };
 
         Some(Self {
             listener,
             spool_dispatcher,
-            slack,
-            matrix,
+            backend,
             terminator,
         })
     }
@@ -121,25 +193,29 @@ impl Daemon {
             Ok(v) => v,
         };
 
-        let matrix = self.matrix;
-        if let Some(matrix) = matrix {
-            let mut matrix = match tokio_runtime.block_on(matrix.login()) {
-                Err(e) => {
-                    error!("{}", e);
-                    return Ok(());
-                }
-                Ok(v) => v,
-            };
-            tokio_runtime.spawn(async move {
-                matrix.run().await;
-            });
-        }
-
-        let slack = self.slack;
-        if let Some(mut slack) = slack {
-            tokio_runtime.spawn(async move {
-                slack.send_messages().await;
-            });
+        match self.backend {
+            Backend::Matrix(mut matrix) => {
+                tokio_runtime.spawn(async move {
+                    if matrix.connect().await {
+                        matrix.run().await;
+                    }
+                });
+            }
+            Backend::Slack(mut slack) => {
+                tokio_runtime.spawn(async move {
+                    slack.send_messages().await;
+                });
+            }
+            Backend::Loki(mut loki) => {
+                tokio_runtime.spawn(async move {
+                    loki.send_messages().await;
+                });
+            }
+            Backend::Alertmanager(mut alertmanager) => {
+                tokio_runtime.spawn(async move {
+                    alertmanager.send_messages().await;
+                });
+            }
         }
 
         let spool_dispatcher = self.spool_dispatcher;
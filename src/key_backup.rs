@@ -0,0 +1,612 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Server-side backup of room keys with the
+//! `m.megolm_backup.v1.curve25519-aes-sha2` algorithm
+
+use crate::config::KeyBackup as KeyBackupConfig;
+use crate::matrix::is_not_found;
+
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs::read_to_string;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use matrix_sdk::ruma::api::client::r0::backup::add_backup_keys;
+use matrix_sdk::ruma::api::client::r0::backup::create_backup;
+use matrix_sdk::ruma::api::client::r0::backup::get_backup_keys;
+use matrix_sdk::ruma::api::client::r0::backup::get_latest_backup;
+use matrix_sdk::ruma::api::client::r0::backup::BackupAlgorithm;
+use matrix_sdk::ruma::api::client::r0::backup::KeyBackupData;
+use matrix_sdk::ruma::api::client::r0::backup::KeyBackupDataInit;
+use matrix_sdk::ruma::api::client::r0::backup::RoomKeyBackup;
+use matrix_sdk::ruma::api::client::r0::backup::SessionData;
+use matrix_sdk::ruma::api::client::r0::backup::SessionDataInit;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::UInt;
+use matrix_sdk::Client;
+use matrix_sdk_crypto::decrypt_key_export;
+use matrix_sdk_crypto::encrypt_key_export;
+use matrix_sdk_crypto::olm::ExportedRoomKey;
+
+use olm_rs::pk::OlmPkDecryption;
+use olm_rs::pk::OlmPkEncryption;
+use olm_rs::pk::PkMessage;
+
+use log::debug;
+use log::info;
+use log::warn;
+
+use thiserror::Error;
+
+/// The alphabet of the base58 encoding of recovery keys
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// The bytes every recovery key starts with
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8b, 0x01];
+
+/// The length of the Curve25519 private key
+const KEY_LENGTH: usize = 32;
+
+/// The temporary export file the room keys pass through between the crypto
+/// store and the backup
+const KEY_EXPORT_FILE: &str = "room-keys.tmp";
+
+/// PBKDF2 rounds of the temporary export file of restored room keys
+const KEY_EXPORT_ROUNDS: u32 = 10000;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid recovery key")]
+    InvalidRecoveryKey,
+
+    #[error("failed to generate recovery key: {0}")]
+    Random(getrandom::Error),
+
+    #[error("invalid session key of session {0}")]
+    InvalidSessionKey(String),
+
+    #[error("failed to decrypt session {0}: {1}")]
+    Decrypt(String, String),
+
+    #[error("invalid session data: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("cannot access recovery key file {0}: {1}")]
+    RecoveryKeyFile(String, std::io::Error),
+
+    #[error("key backup version {0} exists, but recovery key file {1} does not")]
+    MissingRecoveryKey(String, String),
+
+    #[error("key backup version {0} is not encrypted for the recovery key in {1}")]
+    WrongRecoveryKey(String, String),
+
+    #[error("key export task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+
+    #[error("cannot access key export: {0}")]
+    Export(String),
+
+    #[error("matrix request failed: {0}")]
+    Http(#[from] matrix_sdk::HttpError),
+
+    #[error("matrix error: {0}")]
+    Matrix(#[from] matrix_sdk::Error),
+}
+
+/// The server-side backup of the room keys in the crypto store
+pub struct KeyBackup {
+    client: Client,
+
+    config: KeyBackupConfig,
+
+    export_path: PathBuf,
+
+    /// The backup version in use and the key its room keys are encrypted for
+    version: Option<(String, RecoveryKey)>,
+
+    /// Session IDs of the room keys in the backup
+    backed_up: HashSet<String>,
+}
+
+impl KeyBackup {
+    pub fn new(client: Client, config: KeyBackupConfig, state_dir: &Path) -> Self {
+        Self {
+            client,
+            config,
+            export_path: state_dir.join(KEY_EXPORT_FILE),
+            version: None,
+            backed_up: HashSet::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.config.interval.max(1))
+    }
+
+    /// Use the latest backup version and import its room keys, or create the
+    /// first backup version. Without a recovery key file, a new key is written
+    /// there for the first version. Returns whether a new version was created,
+    /// which lacks the room keys known already.
+    ///
+    /// An existing version is never replaced, so a wrong or missing recovery
+    /// key file doesn't orphan it.
+    pub async fn set_up(&mut self) -> Result<bool, Error> {
+        if self.version.is_some() {
+            return Ok(false);
+        }
+
+        let latest = match self
+            .client
+            .send(get_latest_backup::Request::new(), None)
+            .await
+        {
+            Err(e) if is_not_found(&e) => None,
+            Err(e) => return Err(e.into()),
+            Ok(v) => Some(v),
+        };
+        let path = &self.config.recovery_key_file;
+
+        if let Some(latest) = latest {
+            let recovery_key = self
+                .read_recovery_key()?
+                .ok_or_else(|| Error::MissingRecoveryKey(latest.version.clone(), path.clone()))?;
+            if !recovery_key.matches(&latest.algorithm) {
+                return Err(Error::WrongRecoveryKey(latest.version, path.clone()));
+            }
+            self.restore_keys(&recovery_key, &latest.version).await?;
+            self.version = Some((latest.version, recovery_key));
+            return Ok(false);
+        }
+
+        let recovery_key = match self.read_recovery_key()? {
+            Some(v) => v,
+            None => self.write_recovery_key()?,
+        };
+        let request = create_backup::Request::new(recovery_key.algorithm());
+        let response = self.client.send(request, None).await?;
+        info!("Created key backup version {}", response.version);
+        self.backed_up.clear();
+        self.version = Some((response.version, recovery_key));
+        Ok(true)
+    }
+
+    fn read_recovery_key(&self) -> Result<Option<RecoveryKey>, Error> {
+        let path = &self.config.recovery_key_file;
+        match read_to_string(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::RecoveryKeyFile(path.to_string(), e)),
+            Ok(raw) => raw.parse().map(Some),
+        }
+    }
+
+    /// Generate a recovery key and write it to the recovery key file, which
+    /// must not exist yet
+    fn write_recovery_key(&self) -> Result<RecoveryKey, Error> {
+        let path = &self.config.recovery_key_file;
+        let recovery_key = RecoveryKey::generate()?;
+        OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", recovery_key))
+            .map_err(|e| Error::RecoveryKeyFile(path.to_string(), e))?;
+        info!("Wrote new recovery key to {}", path);
+        Ok(recovery_key)
+    }
+
+    /// Import the room keys from the backup, e.g. into a new crypto store.
+    /// Keys known already are skipped.
+    async fn restore_keys(
+        &mut self,
+        recovery_key: &RecoveryKey,
+        version: &str,
+    ) -> Result<(), Error> {
+        let request = get_backup_keys::Request::new(version);
+        let response = self.client.send(request, None).await?;
+
+        let mut keys = Vec::new();
+        for (room_id, room) in &response.rooms {
+            for (session_id, data) in &room.sessions {
+                match recovery_key.decrypt(room_id, session_id, data) {
+                    Err(e) => warn!("Skipping room key in backup: {}", e),
+                    Ok(key) => keys.push(key),
+                }
+                self.backed_up.insert(session_id.clone());
+            }
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        // The matrix SDK imports room keys only from export files
+        let passphrase = recovery_key.to_string();
+        let export = encrypt_key_export(&keys, &passphrase, KEY_EXPORT_ROUNDS)?;
+        tokio::fs::write(&self.export_path, export)
+            .await
+            .map_err(|e| Error::Export(e.to_string()))?;
+        let result = self
+            .client
+            .import_keys(self.export_path.clone(), &passphrase)
+            .await;
+        tokio::fs::remove_file(&self.export_path)
+            .await
+            .map_err(|e| Error::Export(e.to_string()))?;
+        let (imported, total) = result.map_err(|e| Error::Export(e.to_string()))?;
+
+        info!(
+            "Restored {} of {} room keys from backup version {}",
+            imported, total, version
+        );
+        Ok(())
+    }
+
+    /// Upload the room keys which are not in the backup yet
+    pub async fn back_up_keys(&mut self) -> Result<(), Error> {
+        self.set_up().await?;
+        let (version, recovery_key) = match &self.version {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        // The matrix SDK exports room keys only to export files
+        let passphrase = recovery_key.to_string();
+        let backed_up = &self.backed_up;
+        self.client
+            .export_keys(self.export_path.clone(), &passphrase, |session| {
+                !backed_up.contains(session.session_id())
+            })
+            .await?;
+        let export = tokio::fs::read(&self.export_path).await;
+        tokio::fs::remove_file(&self.export_path)
+            .await
+            .map_err(|e| Error::Export(e.to_string()))?;
+        let export = export.map_err(|e| Error::Export(e.to_string()))?;
+        let keys =
+            tokio::task::spawn_blocking(move || decrypt_key_export(export.as_slice(), &passphrase))
+                .await?
+                .map_err(|e| Error::Export(e.to_string()))?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let public_key = recovery_key.public_key();
+        let mut rooms: BTreeMap<RoomId, BTreeMap<String, KeyBackupData>> = BTreeMap::new();
+        for key in &keys {
+            rooms
+                .entry(key.room_id.clone())
+                .or_default()
+                .insert(key.session_id.clone(), encrypt(&public_key, key)?);
+        }
+        let rooms = rooms
+            .into_iter()
+            .map(|(room_id, sessions)| (room_id, RoomKeyBackup::new(sessions)))
+            .collect();
+
+        let request = add_backup_keys::Request::new(version, rooms);
+        self.client.send(request, None).await?;
+        debug!(
+            "Uploaded {} room keys to backup version {}",
+            keys.len(),
+            version
+        );
+        self.backed_up
+            .extend(keys.into_iter().map(|key| key.session_id));
+        Ok(())
+    }
+}
+
+/// The private Curve25519 key the room keys in a backup are encrypted for
+struct RecoveryKey {
+    key: [u8; KEY_LENGTH],
+}
+
+impl RecoveryKey {
+    fn generate() -> Result<Self, Error> {
+        let mut key = [0; KEY_LENGTH];
+        getrandom::getrandom(&mut key).map_err(Error::Random)?;
+        Ok(Self { key })
+    }
+
+    /// The public key as given in the `auth_data` of a backup version
+    fn public_key(&self) -> String {
+        self.decryption().public_key().to_string()
+    }
+
+    /// The algorithm of a new backup version for this key. It is not signed,
+    /// so clients show the backup as untrusted until it is restored with the
+    /// recovery key.
+    fn algorithm(&self) -> BackupAlgorithm {
+        BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 {
+            public_key: self.public_key(),
+            signatures: BTreeMap::new(),
+        }
+    }
+
+    /// Whether a backup version is encrypted for this key
+    fn matches(&self, algorithm: &BackupAlgorithm) -> bool {
+        match algorithm {
+            BackupAlgorithm::MegolmBackupV1Curve25519AesSha2 { public_key, .. } => {
+                *public_key == self.public_key()
+            }
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Decrypt a room key from the backup
+    fn decrypt(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+        data: &KeyBackupData,
+    ) -> Result<ExportedRoomKey, Error> {
+        let message = PkMessage::new(
+            data.session_data.ephemeral.clone(),
+            data.session_data.mac.clone(),
+            data.session_data.ciphertext.clone(),
+        );
+        let plaintext = self
+            .decryption()
+            .decrypt(message)
+            .map_err(|e| Error::Decrypt(session_id.to_string(), e.to_string()))?;
+
+        let mut session: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(&plaintext)?;
+        session.insert("room_id".to_string(), room_id.as_str().into());
+        session.insert("session_id".to_string(), session_id.into());
+        Ok(serde_json::from_value(session.into())?)
+    }
+
+    fn decryption(&self) -> OlmPkDecryption {
+        OlmPkDecryption::from_bytes(&self.key).expect("private key has the right length")
+    }
+}
+
+/// Encrypt a room key for the backup with the given public key
+fn encrypt(public_key: &str, key: &ExportedRoomKey) -> Result<KeyBackupData, Error> {
+    let first_message_index =
+        first_message_index(key).ok_or_else(|| Error::InvalidSessionKey(key.session_id.clone()))?;
+
+    // The room and session are given by the position in the backup
+    let mut session = match serde_json::to_value(key)? {
+        serde_json::Value::Object(v) => v,
+        _ => return Err(Error::InvalidSessionKey(key.session_id.clone())),
+    };
+    session.remove("room_id");
+    session.remove("session_id");
+
+    let message =
+        OlmPkEncryption::new(public_key).encrypt(&serde_json::Value::Object(session).to_string());
+    let session_data: SessionData = SessionDataInit {
+        ephemeral: message.ephemeral_key,
+        ciphertext: message.ciphertext,
+        mac: message.mac,
+    }
+    .into();
+
+    Ok(KeyBackupDataInit {
+        first_message_index: first_message_index.into(),
+        forwarded_count: UInt::new(key.forwarding_curve25519_key_chain.len() as u64)
+            .unwrap_or(UInt::MAX),
+        is_verified: false,
+        session_data,
+    }
+    .into())
+}
+
+/// The index of the first message the exported session key can decrypt,
+/// which follows the version byte
+fn first_message_index(key: &ExportedRoomKey) -> Option<u32> {
+    let raw = base64::decode_config(&key.session_key.0, base64::STANDARD_NO_PAD).ok()?;
+    let index = raw.get(1..5)?;
+    Some(u32::from_be_bytes([index[0], index[1], index[2], index[3]]))
+}
+
+impl FromStr for RecoveryKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let raw = base58_decode(&s.split_whitespace().collect::<String>())
+            .ok_or(Error::InvalidRecoveryKey)?;
+        if raw.len() != RECOVERY_KEY_PREFIX.len() + KEY_LENGTH + 1
+            || raw[..RECOVERY_KEY_PREFIX.len()] != RECOVERY_KEY_PREFIX
+            || raw.iter().fold(0, |parity, byte| parity ^ byte) != 0
+        {
+            return Err(Error::InvalidRecoveryKey);
+        }
+
+        let mut key = [0; KEY_LENGTH];
+        key.copy_from_slice(
+            &raw[RECOVERY_KEY_PREFIX.len()..RECOVERY_KEY_PREFIX.len() + KEY_LENGTH],
+        );
+        Ok(Self { key })
+    }
+}
+
+/// The recovery key as shown by clients, in groups of four characters
+impl Display for RecoveryKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut raw = RECOVERY_KEY_PREFIX.to_vec();
+        raw.extend_from_slice(&self.key);
+        raw.push(raw.iter().fold(0, |parity, byte| parity ^ byte));
+
+        let encoded = base58_encode(&raw);
+        let groups: Vec<_> = encoded
+            .as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).unwrap_or_default())
+            .collect();
+        write!(f, "{}", groups.join(" "))
+    }
+}
+
+fn base58_encode(input: &[u8]) -> String {
+    // Little-endian digits in base 58
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in input {
+        let mut carry = u32::from(*byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let zeros = input.iter().take_while(|byte| **byte == 0).count();
+    std::iter::repeat(BASE58_ALPHABET[0] as char)
+        .take(zeros)
+        .chain(
+            digits
+                .iter()
+                .rev()
+                .map(|digit| BASE58_ALPHABET[*digit as usize] as char),
+        )
+        .collect()
+}
+
+fn base58_decode(input: &str) -> Option<Vec<u8>> {
+    // Little-endian bytes
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in input.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    let zeros = input
+        .bytes()
+        .take_while(|c| *c == BASE58_ALPHABET[0])
+        .count();
+    Some(
+        std::iter::repeat(0)
+            .take(zeros)
+            .chain(bytes.into_iter().rev())
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+
+    #[test]
+    fn base58_round_trip() {
+        let input = [0, 0, 1, 2, 255, 58, 0];
+        assert_eq!(Some(input.to_vec()), base58_decode(&base58_encode(&input)));
+    }
+
+    #[test]
+    fn base58_known_value() {
+        assert_eq!("5Q", base58_encode(&[255]));
+        assert_eq!("1112", base58_encode(&[0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn recovery_key_round_trip() {
+        let key = RecoveryKey::generate().unwrap();
+
+        let parsed: RecoveryKey = key.to_string().parse().unwrap();
+
+        assert_eq!(key.key, parsed.key);
+        assert!(key.to_string().starts_with('E'));
+    }
+
+    #[test]
+    fn recovery_key_with_wrong_parity_is_rejected() {
+        let mut raw = RECOVERY_KEY_PREFIX.to_vec();
+        raw.extend_from_slice(&[1; KEY_LENGTH]);
+        raw.push(0);
+
+        let result = base58_encode(&raw).parse::<RecoveryKey>();
+
+        assert!(matches!(result, Err(Error::InvalidRecoveryKey)));
+    }
+
+    #[test]
+    fn room_key_round_trip() {
+        let recovery_key = RecoveryKey::generate().unwrap();
+        let mut session_key = vec![2, 0, 0, 0, 7];
+        session_key.extend_from_slice(&[0; 160]);
+        let key: ExportedRoomKey = serde_json::from_value(serde_json::json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "room_id": "!room:hs.example",
+            "sender_key": "sender",
+            "session_id": "session",
+            "session_key": base64::encode_config(&session_key, base64::STANDARD_NO_PAD),
+            "sender_claimed_keys": {"ed25519": "claimed"},
+            "forwarding_curve25519_key_chain": ["forwarder"],
+        }))
+        .unwrap();
+
+        let data = encrypt(&recovery_key.public_key(), &key).unwrap();
+        let room_id = RoomId::try_from("!room:hs.example").unwrap();
+        let decrypted = recovery_key.decrypt(&room_id, "session", &data).unwrap();
+
+        assert_eq!(UInt::from(7u32), data.first_message_index);
+        assert_eq!(UInt::from(1u32), data.forwarded_count);
+        assert_eq!(
+            serde_json::to_value(&key).unwrap(),
+            serde_json::to_value(&decrypted).unwrap()
+        );
+    }
+
+    #[test]
+    fn room_key_for_other_recovery_key_fails() {
+        let recovery_key = RecoveryKey::generate().unwrap();
+        let other = RecoveryKey::generate().unwrap();
+        let key: ExportedRoomKey = serde_json::from_value(serde_json::json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "room_id": "!room:hs.example",
+            "sender_key": "sender",
+            "session_id": "session",
+            "session_key": base64::encode_config([1u8; 165], base64::STANDARD_NO_PAD),
+            "sender_claimed_keys": {},
+            "forwarding_curve25519_key_chain": [],
+        }))
+        .unwrap();
+
+        let data = encrypt(&other.public_key(), &key).unwrap();
+        let room_id = RoomId::try_from("!room:hs.example").unwrap();
+
+        assert!(recovery_key.decrypt(&room_id, "session", &data).is_err());
+        assert!(!recovery_key.matches(&other.algorithm()));
+        assert!(recovery_key.matches(&recovery_key.algorithm()));
+    }
+}
@@ -0,0 +1,235 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::message::Level;
+use crate::message::Message;
+
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+
+use tokio::fs::rename;
+use tokio::fs::write;
+
+use chrono::Duration;
+use chrono::Local;
+use chrono::TimeZone;
+
+use serde_derive::Deserialize;
+use serde_derive::Serialize;
+
+use log::debug;
+use log::error;
+use log::warn;
+
+/// How many earlier states of a keyed message are shown
+const MAX_HISTORY: usize = 10;
+
+/// Keys which have not been updated for this many days are forgotten
+const MAX_AGE_DAYS: i64 = 30;
+
+/// Where a keyed message was sent to and which states it went through
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Sent {
+    /// The room or channel of the message
+    pub room: String,
+
+    /// The ID of the message in the room
+    pub id: String,
+
+    /// The channel the message was addressed to if it is not `room`, like
+    /// a Slack channel name resolved to its ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addressed: Option<String>,
+
+    pub history: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub level: Level,
+
+    /// Seconds since the epoch
+    pub timestamp: i64,
+}
+
+impl Sent {
+    pub fn new(room: &str, id: &str, message: &Message) -> Self {
+        Sent {
+            room: room.to_string(),
+            id: id.to_string(),
+            addressed: None,
+            history: vec![Entry::from(message)],
+        }
+    }
+
+    /// Whether the message was sent to or addressed to `channel`
+    pub fn is_in(&self, channel: &str) -> bool {
+        self.room == channel || self.addressed.as_deref() == Some(channel)
+    }
+
+    pub fn push(&mut self, message: &Message) {
+        self.history.push(Entry::from(message));
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+    }
+
+    /// The message with the earlier states appended to its text
+    pub fn with_history(&self, message: &Message) -> Message {
+        let mut message = message.clone();
+        let history = self
+            .history
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} {}",
+                    Local.timestamp(entry.timestamp, 0).format("%F %T"),
+                    entry.level.name()
+                )
+            })
+            .collect::<Vec<_>>();
+        if !history.is_empty() {
+            message.text = format!("{}\n\nEarlier: {}", message.text, history.join(", "));
+        }
+        message
+    }
+}
+
+impl From<&Message> for Entry {
+    fn from(message: &Message) -> Self {
+        Entry {
+            level: message.level.clone(),
+            timestamp: message.timestamp.timestamp(),
+        }
+    }
+}
+
+/// The key under which the root message of `thread` in `channel` is kept.
+/// It changes daily so threads don't grow forever.
+fn thread_key(channel: &str, thread: &str) -> String {
+    format!(
+        "thread:{}:{}:{}",
+        channel,
+        thread,
+        Local::now().format("%F")
+    )
+}
+
+/// The text of a thread root message
+pub fn thread_title(thread: &str) -> String {
+    format!("{} ({})", thread, Local::now().format("%F"))
+}
+
+/// The messages sent with a `--key` and the thread roots, persisted so they
+/// can be updated or replied to after a restart
+pub struct KeyStore {
+    path: String,
+
+    entries: Entries,
+}
+
+/// Thread roots are kept apart from the keyed messages so no `--key` can
+/// refer to them
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct Entries {
+    keys: BTreeMap<String, Sent>,
+
+    threads: BTreeMap<String, Sent>,
+}
+
+impl KeyStore {
+    pub fn load(path: &str) -> Self {
+        let entries: Entries = match read_to_string(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Entries::default(),
+            Err(e) => {
+                error!("Failed to read message keys from {}: {}", path, e);
+                Entries::default()
+            }
+            Ok(raw) => match serde_json::from_str(&raw) {
+                Err(e) => {
+                    error!("Discarding invalid message keys in {}: {}", path, e);
+                    Entries::default()
+                }
+                Ok(v) => v,
+            },
+        };
+        debug!(
+            "Loaded {} message keys and {} threads",
+            entries.keys.len(),
+            entries.threads.len()
+        );
+
+        KeyStore {
+            path: path.to_string(),
+            entries,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Sent> {
+        self.entries.keys.get(key)
+    }
+
+    pub async fn insert(&mut self, key: &str, sent: Sent) {
+        self.entries.keys.insert(key.to_string(), sent);
+        self.expire();
+        self.store().await;
+    }
+
+    /// The root message of today's `thread` in `channel`
+    pub fn thread(&self, channel: &str, thread: &str) -> Option<&Sent> {
+        self.entries.threads.get(&thread_key(channel, thread))
+    }
+
+    pub async fn insert_thread(&mut self, channel: &str, thread: &str, root: Sent) {
+        self.entries
+            .threads
+            .insert(thread_key(channel, thread), root);
+        self.expire();
+        self.store().await;
+    }
+
+    fn expire(&mut self) {
+        let oldest = (Local::now() - Duration::days(MAX_AGE_DAYS)).timestamp();
+        let recent = |_: &String, sent: &mut Sent| {
+            sent.history
+                .last()
+                .map_or(false, |entry| entry.timestamp >= oldest)
+        };
+        self.entries.keys.retain(recent);
+        self.entries.threads.retain(recent);
+    }
+
+    async fn store(&self) {
+        let raw = match serde_json::to_vec(&self.entries) {
+            Err(e) => {
+                error!("Failed to serialise message keys: {}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+
+        let temporary = format!("{}.tmp", self.path);
+        let result = match write(&temporary, raw).await {
+            Ok(()) => rename(&temporary, &self.path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to store message keys in {}: {}", self.path, e);
+        }
+    }
+}
@@ -15,18 +15,25 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::message::Confirmation;
 use crate::message::Message;
 use crate::message::Packet;
-use crate::message::Sas;
+use crate::message::Verification;
+use crate::message::VerifyCommand;
 
 use nix::errno;
 use nix::sys::stat;
 
-use tokio::io::AsyncReadExt;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::unix::OwnedReadHalf;
+use tokio::net::unix::OwnedWriteHalf;
 use tokio::net::UnixListener;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
 use tokio_stream::StreamExt;
 
 use tokio_stream::wrappers::UnixListenerStream;
@@ -39,8 +46,28 @@ use log::debug;
 use log::error;
 use log::warn;
 
+use serde::Serialize;
+
 use thiserror::Error;
 
+/// Requests of `alert --verify` to the verifier of the backend
+pub enum VerifyRequest {
+    /// Wait for the verification with this ID or the next one not shown yet
+    Show {
+        id: Option<String>,
+        reply: oneshot::Sender<Verification>,
+    },
+
+    List(oneshot::Sender<Vec<Verification>>),
+
+    Cancel {
+        id: String,
+        reply: oneshot::Sender<bool>,
+    },
+
+    Answer(Confirmation),
+}
+
 pub struct Listener {
     socket_path: String,
 
@@ -48,7 +75,9 @@ pub struct Listener {
 
     slack: Sender<Message>,
 
-    verifier: UnboundedSender<Sas>,
+    verifier: UnboundedSender<VerifyRequest>,
+
+    max_attachment_size: usize,
 
     terminator: Receiver<()>,
 }
@@ -59,13 +88,16 @@ pub enum Error {
     StdIoError(#[from] std::io::Error),
     #[error("")]
     NixError(#[from] nix::Error),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
 }
 
 impl Listener {
     pub fn new(
         socket_path: &str,
         slack: Sender<Message>,
-        verifier: UnboundedSender<Sas>,
+        verifier: UnboundedSender<VerifyRequest>,
+        max_attachment_size: usize,
         terminator: Receiver<()>,
     ) -> Self {
         Self {
@@ -73,6 +105,7 @@ impl Listener {
             listener: None,
             slack,
             verifier,
+            max_attachment_size,
             terminator,
         }
     }
@@ -91,15 +124,17 @@ impl Listener {
                             debug!("Listener shutting down");
                             return;
                         }
-                        Some(Ok(mut stream)) => {
+                        Some(Ok(stream)) => {
+                            let (reader, writer) = stream.into_split();
+                            let mut reader = BufReader::new(reader);
 
                             let mut string = String::new();
-                            if let Err(e) = stream.read_to_string(&mut string).await {
+                            if let Err(e) = reader.read_line(&mut string).await {
                                 error!("Failed to read from socket: {}", e);
                                 continue;
                             }
 
-                            if let Err(e) = self.transmit_message(string).await {
+                            if let Err(e) = self.transmit_message(string, reader, writer).await {
                                 error!("Failed to transmit message: {:#?}", e);
                                 continue;
                             }
@@ -117,7 +152,12 @@ impl Listener {
         }
     }
 
-    async fn transmit_message(&mut self, message: String) -> Result<(), Error> {
+    async fn transmit_message(
+        &mut self,
+        message: String,
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    ) -> Result<(), Error> {
         let message: Result<Packet, serde_json::error::Error> = serde_json::from_str(&message);
         if let Err(e) = message {
             warn!("Could not read request: {}", e);
@@ -126,13 +166,19 @@ impl Listener {
         let message = message.unwrap();
 
         match message {
-            Packet::Sas(sas) => {
-                debug!("Local verification received");
-                if let Err(e) = self.verifier.send(sas) {
-                    warn!("Could not send verification input: {:#?}", e);
-                }
+            Packet::Verify(command) => {
+                debug!("Local verification command {:?} received", command);
+                let verifier = self.verifier.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = verify(command, reader, writer, verifier).await {
+                        warn!("Interactive verification failed: {}", e);
+                    }
+                });
             }
-            Packet::Message(message) => {
+            Packet::Message(mut message) => {
+                for attachment in &mut message.attachments {
+                    attachment.truncate(self.max_attachment_size);
+                }
                 if let Err(e) = self.slack.send(message).await {
                     warn!("Could not send message: {:#?}", e);
                 }
@@ -172,3 +218,86 @@ impl Listener {
         Ok(UnixListenerStream::new(listener))
     }
 }
+
+/// Pass a command of `alert --verify` to the verifier and answer it. A shown
+/// verification is cancelled if `alert --verify` goes away without answering.
+async fn verify(
+    command: VerifyCommand,
+    mut reader: BufReader<OwnedReadHalf>,
+    mut writer: OwnedWriteHalf,
+    verifier: UnboundedSender<VerifyRequest>,
+) -> Result<(), Error> {
+    let id = match command {
+        VerifyCommand::List => {
+            let (reply, verifications) = oneshot::channel();
+            if send_request(&verifier, VerifyRequest::List(reply)) {
+                if let Ok(verifications) = verifications.await {
+                    write_line(&mut writer, &verifications).await?;
+                }
+            }
+            return Ok(());
+        }
+        VerifyCommand::Cancel(id) => {
+            let (reply, cancelled) = oneshot::channel();
+            if send_request(&verifier, VerifyRequest::Cancel { id, reply }) {
+                if let Ok(cancelled) = cancelled.await {
+                    write_line(&mut writer, &cancelled).await?;
+                }
+            }
+            return Ok(());
+        }
+        VerifyCommand::Show(id) => id,
+    };
+
+    let (reply, verification) = oneshot::channel();
+    if !send_request(&verifier, VerifyRequest::Show { id, reply }) {
+        return Ok(());
+    }
+
+    let mut line = String::new();
+    let verification = tokio::select! {
+        verification = verification => match verification {
+            Err(_) => return Ok(()),
+            Ok(v) => v,
+        },
+        _ = reader.read_line(&mut line) => {
+            debug!("alert --verify went away while waiting");
+            return Ok(());
+        }
+    };
+
+    write_line(&mut writer, &verification).await?;
+
+    line.clear();
+    if let Err(e) = reader.read_line(&mut line).await {
+        warn!("Failed to read answer to verification: {}", e);
+    }
+    let confirmation = match serde_json::from_str::<Confirmation>(&line) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Invalid answer to verification {}: {}", verification.id, e);
+            Confirmation {
+                id: verification.id,
+                confirmed: false,
+            }
+        }
+    };
+    send_request(&verifier, VerifyRequest::Answer(confirmation));
+
+    Ok(())
+}
+
+fn send_request(verifier: &UnboundedSender<VerifyRequest>, request: VerifyRequest) -> bool {
+    if verifier.send(request).is_err() {
+        warn!("Verification is not available with this backend");
+        return false;
+    }
+    true
+}
+
+async fn write_line<T: Serialize>(writer: &mut OwnedWriteHalf, value: &T) -> Result<(), Error> {
+    let mut raw = serde_json::to_string(value)?;
+    raw.push('\n');
+    writer.write_all(raw.as_bytes()).await?;
+    Ok(())
+}
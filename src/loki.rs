@@ -0,0 +1,240 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::config::Loki as LokiConfig;
+use crate::message::Message;
+use crate::spool_dispatcher::Report;
+use crate::util;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use reqwest::header::CONTENT_ENCODING;
+use reqwest::header::CONTENT_TYPE;
+
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::Sender;
+use tokio::time::timeout_at;
+use tokio::time::Instant;
+
+use serde_derive::Serialize;
+
+use thiserror::Error;
+
+use log::debug;
+use log::warn;
+
+const TENANT_HEADER: &str = "X-Scope-OrgID";
+
+type Labels = BTreeMap<String, String>;
+
+#[derive(Debug, Serialize)]
+struct PushRequest {
+    streams: Vec<Stream>,
+}
+
+#[derive(Debug, Serialize)]
+struct Stream {
+    stream: Labels,
+
+    values: Vec<[String; 2]>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to serialise message: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("failed to compress request: {0}")]
+    Compression(#[from] std::io::Error),
+
+    #[error("failed to push to loki: {0}")]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("loki replied with status {0}")]
+    StatusCode(u16),
+}
+
+pub struct Loki {
+    config: LokiConfig,
+
+    inline_size: usize,
+
+    spooler: Receiver<Message>,
+
+    send_reporter: Sender<Report>,
+
+    terminator: tokio::sync::broadcast::Receiver<()>,
+}
+
+impl Loki {
+    pub fn new(
+        spooler: Receiver<Message>,
+        send_reporter: Sender<Report>,
+        config: LokiConfig,
+        inline_size: usize,
+        terminator: tokio::sync::broadcast::Receiver<()>,
+    ) -> Self {
+        Loki {
+            config,
+            inline_size,
+            spooler,
+            send_reporter,
+            terminator,
+        }
+    }
+
+    pub async fn send_messages(&mut self) {
+        loop {
+            let batch = tokio::select! {
+                next = self.spooler.recv() => {
+                    if let Some(message) = next {
+                        self.collect_batch(message).await
+                    } else {
+                        debug!("Loki shutting down because spooler is down");
+                        return;
+                    }
+                }
+                _ = self.terminator.recv() => {
+                    debug!("Loki shutting down on termination signal");
+                    return;
+                }
+            };
+
+            debug!("Pushing {} messages", batch.len());
+            let succeeded = match self.push(&batch).await {
+                Err(e) => {
+                    warn!("Error while pushing: {}", e);
+                    false
+                }
+                Ok(()) => true,
+            };
+
+            for message in batch {
+                let report = if succeeded {
+                    Report::Sent
+                } else {
+                    Report::Failed(message)
+                };
+                if self.send_reporter.send(report).await.is_err() {
+                    debug!("Loki shutting down because send_reporter is down");
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn collect_batch(&mut self, first: Message) -> Vec<Message> {
+        let deadline = Instant::now() + Duration::from_secs(self.config.batch_timeout);
+        let mut batch = vec![first];
+
+        while batch.len() < self.config.batch_size {
+            match timeout_at(deadline, self.spooler.recv()).await {
+                Ok(Some(message)) => batch.push(message),
+                _ => break,
+            }
+        }
+
+        batch
+    }
+
+    async fn push(&self, batch: &[Message]) -> Result<(), Error> {
+        let request = self.build_request(batch)?;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&serde_json::to_vec(&request)?)?;
+        let body = encoder.finish()?;
+
+        let client = reqwest::Client::builder().build()?;
+
+        let mut builder = client
+            .post(format!(
+                "{}/loki/api/v1/push",
+                self.config.url.trim_end_matches('/')
+            ))
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(body);
+
+        if let Some(tenant) = &self.config.tenant {
+            builder = builder.header(TENANT_HEADER, tenant);
+        }
+
+        if let Some(username) = &self.config.username {
+            builder = builder.basic_auth(username, self.config.password.as_ref());
+        }
+
+        let response = builder.send().await?;
+
+        match response.status().as_u16() {
+            200 | 204 => Ok(()),
+            code => {
+                warn!("Upstream reported error: {:#?}", response);
+                Err(Error::StatusCode(code))
+            }
+        }
+    }
+
+    fn build_request(&self, batch: &[Message]) -> Result<PushRequest, Error> {
+        let host = util::hostname();
+        let mut streams: BTreeMap<Labels, Vec<(i64, String)>> = BTreeMap::new();
+
+        for message in batch {
+            streams
+                .entry(self.labels(message, &host))
+                .or_default()
+                .push((
+                    message.timestamp.timestamp_nanos(),
+                    serde_json::to_string(&message.inline_attachments(self.inline_size))?,
+                ));
+        }
+
+        let streams = streams
+            .into_iter()
+            .map(|(stream, mut values)| {
+                values.sort_by_key(|(timestamp, _)| *timestamp);
+                Stream {
+                    stream,
+                    values: values
+                        .into_iter()
+                        .map(|(timestamp, line)| [timestamp.to_string(), line])
+                        .collect(),
+                }
+            })
+            .collect();
+
+        Ok(PushRequest { streams })
+    }
+
+    fn labels(&self, message: &Message, host: &str) -> Labels {
+        let mut labels = Labels::new();
+        labels.insert("host".to_string(), host.to_string());
+        labels.insert("level".to_string(), message.level.name().to_string());
+
+        for key in &self.config.labels {
+            if let Some(value) = message.fields.get(key) {
+                labels.insert(util::sanitise_label_name(key), value.to_string());
+            }
+        }
+
+        labels
+    }
+}
@@ -15,13 +15,19 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+pub mod acks;
 pub mod alert_cli_parser;
+pub mod alertmanager;
 pub mod backoff;
 pub mod cli_parser;
+pub mod commands;
 pub mod config;
 pub mod daemon;
+pub mod key_backup;
+pub mod key_store;
 pub mod listener;
 pub mod logging;
+pub mod loki;
 pub mod matrix;
 pub mod message;
 pub mod slack;
@@ -29,6 +35,7 @@ pub mod spool_dispatcher;
 pub mod spooler;
 pub mod systemd;
 pub mod terminator;
+pub mod token_bucket;
 pub mod util;
 
 use config::Config;
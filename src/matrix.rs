@@ -15,68 +15,251 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::fs::create_dir_all;
+use std::fs::metadata;
+use std::fs::read;
+use std::fs::read_to_string;
+use std::fs::write;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-
+use std::time::UNIX_EPOCH;
+
+use crate::acks;
+use crate::acks::Acks;
+use crate::backoff::Backoff;
+use crate::commands::CommandHandler;
+use crate::commands::Silences;
+use crate::config::Matrix as MatrixConfig;
+use crate::config::Mentions;
+use crate::config::MsgType;
+use crate::config::RoomProvisioning;
+use crate::config::TrustPolicy;
+use crate::key_backup;
+use crate::key_backup::KeyBackup;
+use crate::key_store;
+use crate::key_store::KeyStore;
+use crate::key_store::Sent;
+use crate::listener::VerifyRequest;
+use crate::message::Emoji;
 use crate::message::Message;
-use crate::message::Sas;
+use crate::message::Verification as LocalVerification;
+use crate::spool_dispatcher::Control;
+use crate::spool_dispatcher::Report;
+use crate::systemd;
 use crate::util;
 
 use matrix_sdk::instant::Duration;
+use matrix_sdk::room::Joined;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+use matrix_sdk::ruma::api::client::r0::alias::get_alias;
+use matrix_sdk::ruma::api::client::r0::config::get_global_account_data;
+use matrix_sdk::ruma::api::client::r0::config::set_global_account_data;
+use matrix_sdk::ruma::api::client::r0::keys::get_keys;
+use matrix_sdk::ruma::api::client::r0::room::create_room;
+use matrix_sdk::ruma::api::client::r0::room::create_room::RoomPreset;
+use matrix_sdk::ruma::api::client::r0::state::send_state_event;
+use matrix_sdk::ruma::api::client::r0::uiaa::AuthData;
+use matrix_sdk::ruma::api::client::r0::uiaa::Password;
+use matrix_sdk::ruma::api::client::r0::uiaa::UserIdentifier;
+use matrix_sdk::ruma::api::error::FromHttpResponseError;
+use matrix_sdk::ruma::api::error::ServerError;
+use matrix_sdk::ruma::assign;
+use matrix_sdk::ruma::encryption::CrossSigningKey;
+use matrix_sdk::ruma::encryption::DeviceKeys;
+use matrix_sdk::ruma::events::custom::CustomEventContent;
 use matrix_sdk::ruma::events::key::verification::ShortAuthenticationString;
 use matrix_sdk::ruma::events::key::verification::VerificationMethod;
+use matrix_sdk::ruma::events::room::member::MemberEventContent;
+use matrix_sdk::ruma::events::room::member::MembershipState;
+use matrix_sdk::ruma::events::room::message::InReplyTo;
 use matrix_sdk::ruma::events::room::message::MessageEventContent;
 use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::events::room::message::NoticeMessageEventContent;
+use matrix_sdk::ruma::events::room::message::Relation;
+use matrix_sdk::ruma::events::room::message::Replacement;
 use matrix_sdk::ruma::events::AnyMessageEventContent;
+use matrix_sdk::ruma::events::AnyStateEventContent;
+use matrix_sdk::ruma::events::AnyStrippedStateEvent;
 use matrix_sdk::ruma::events::AnySyncMessageEvent;
 use matrix_sdk::ruma::events::AnySyncRoomEvent;
 use matrix_sdk::ruma::events::AnyToDeviceEvent;
+use matrix_sdk::ruma::identifiers::RoomName;
+use matrix_sdk::ruma::serde::Raw;
+use matrix_sdk::ruma::DeviceKeyId;
+use matrix_sdk::ruma::EventId;
+use matrix_sdk::ruma::RoomAliasId;
+use matrix_sdk::ruma::RoomId;
+use matrix_sdk::ruma::UserId;
 use matrix_sdk::verification::SasVerification as RemoteSas;
 use matrix_sdk::verification::Verification;
 use matrix_sdk::Client;
 use matrix_sdk::ClientConfig;
+use matrix_sdk::HttpError;
 use matrix_sdk::LoopCtrl;
+use matrix_sdk::Session;
 use matrix_sdk::SyncSettings;
 
 use matrix_sdk_crypto::AcceptSettings;
+use matrix_sdk_crypto::LocalTrust;
+use olm_rs::utility::OlmUtility;
 
 use thiserror::Error;
 
 use url::Url;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::value::to_raw_value;
+use serde_json::Value as JsonValue;
+
+use reqwest::StatusCode;
 
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+use tokio::time::interval;
+use tokio::time::sleep;
+use tokio::time::sleep_until;
+use tokio::time::Instant;
 
-use tera::Context;
 use tera::Tera;
 
 use log::debug;
+use log::error;
 use log::info;
 use log::trace;
 use log::warn;
 
+const SESSION_FILE: &str = "session.json";
+
+const AVATAR_FILE: &str = "avatar.json";
+
+/// The file mapping hosts or field values to provisioned rooms
+const PROVISIONED_ROOMS_FILE: &str = "rooms.json";
+
+/// The file listing the devices blacklisted by the trust policy
+const BLACKLIST_FILE: &str = "blacklist.json";
+
+/// The `.html` suffix makes tera escape the inserted values
+const HTML_TEMPLATE: &str = "message.html";
+
+const PLAIN_TEMPLATE: &str = "message.txt";
+
+/// How often to try resolving the homeserver before giving up
+const WELL_KNOWN_ATTEMPTS: u32 = 5;
+
+/// Upper bound of the delay between login attempts in seconds
+const MAX_LOGIN_BACKOFF: u64 = 300;
+
+/// Verifications not finished within this time are cancelled, like other
+/// clients do
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(600);
+
+const VERIFICATION_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait for a room to show up as joined in the sync
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct Matrix {
     client: Client,
 
     username: String,
 
-    password: String,
+    server: String,
+
+    /// Whether the homeserver URL was configured and needs no discovery
+    homeserver_configured: bool,
+
+    user_id: UserId,
+
+    password: Option<String>,
+
+    access_token: Option<String>,
+
+    device_id: Option<String>,
+
+    /// Where the session of the last login is persisted
+    session_path: PathBuf,
 
     channel: String,
 
+    /// Room IDs of resolved room aliases and direct message partners
+    rooms: HashMap<String, RoomId>,
+
+    invite_allowlist: Option<Vec<String>>,
+
+    mentions: Mentions,
+
+    msgtype: MsgType,
+
+    display_name: Option<String>,
+
+    room_display_names: BTreeMap<String, String>,
+
+    avatar: Option<String>,
+
+    /// Where the size, modification time and URI of the last uploaded avatar
+    /// are kept
+    avatar_state_path: PathBuf,
+
+    cross_signing: bool,
+
+    cross_signing_devices: Vec<String>,
+
+    trust_policy: TrustPolicy,
+
+    /// User and device IDs of the devices blacklisted by `verified_only`, so
+    /// only these are released again
+    blacklist: BTreeSet<(String, String)>,
+
+    blacklist_path: PathBuf,
+
+    /// Shared with the task uploading the room keys
+    key_backup: Option<Arc<tokio::sync::Mutex<KeyBackup>>>,
+
+    /// Whether room keys may have been created since the last backup
+    keys_changed: Arc<AtomicBool>,
+
+    room_provisioning: Option<RoomProvisioning>,
+
+    /// Users invited to provisioned rooms
+    provisioning_invites: Vec<UserId>,
+
+    /// Room IDs of the provisioned rooms by host or field value
+    provisioned_rooms: BTreeMap<String, String>,
+
+    provisioned_rooms_path: PathBuf,
+
+    commands: CommandHandler,
+
+    silences: Silences,
+
+    acks: Acks,
+
+    key_store: KeyStore,
+
     tera: Tera,
 
     spooler: Receiver<Message>,
 
-    send_reporter: Sender<Option<Message>>,
+    send_reporter: Sender<Report>,
 
-    verifier: Option<UnboundedReceiver<Sas>>,
+    verifier: Option<UnboundedReceiver<VerifyRequest>>,
 }
 
 #[derive(Error, Debug)]
@@ -91,48 +274,349 @@ pub enum Error {
     #[error("username or password wrong")]
     InvalidLogin,
 
-    #[error("room name wrong")]
-    InvalidRoom,
+    #[error("either password or access_token is required")]
+    MissingCredentials,
+
+    #[error("access_token requires device_id to be set")]
+    MissingDeviceId,
+
+    #[error("failed to access state directory: {0}")]
+    StateDir(#[from] std::io::Error),
+
+    #[error("room has unverified devices: {0}")]
+    UnverifiedDevices(String),
+
+    #[error("key backup failed: {0}")]
+    KeyBackup(#[from] key_backup::Error),
+
+    #[error("bootstrapping cross-signing requires a password")]
+    CrossSigningNeedsPassword,
+
+    #[error("failed to read avatar: {0}")]
+    Avatar(std::io::Error),
+
+    #[error("persisted session is invalid: {0}")]
+    InvalidSession(serde_json::Error),
+
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("'{0}' is neither a room ID, a room alias nor a user ID")]
+    InvalidChannel(String),
+
+    #[error("'{0}' in room_provisioning is not a user ID")]
+    InvalidInvitee(String),
+
+    #[error("room {0} is not joined")]
+    NotJoined(String),
+
+    #[error("room alias {0} does not exist")]
+    UnknownAlias(String),
+
+    #[error("message with key '{0}' has an invalid event ID")]
+    InvalidKeyedEvent(String),
 
-    #[error("matrix error")]
+    #[error("matrix request failed: {0}")]
+    Http(#[from] matrix_sdk::HttpError),
+
+    #[error("matrix error: {0}")]
     Matrix(#[from] matrix_sdk::Error),
 
-    #[error("matrix server resolution failed")]
+    #[error("failed to check access token: {0}")]
+    TokenCheck(reqwest::Error),
+
+    #[error("matrix server resolution failed: {0}")]
     MatrixWellKnown(#[from] reqwest::Error),
 
     #[error("message template is invalid: {0:#?}")]
     Tera(#[from] tera::Error),
 }
 
+impl Error {
+    /// Whether retrying the same message cannot succeed
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidChannel(_)
+                | Error::UnknownAlias(_)
+                | Error::InvalidKeyedEvent(_)
+                | Error::UnverifiedDevices(_)
+                | Error::Tera(_)
+        )
+    }
+}
+
+/// Relate a message to the thread below `root`. Clients without thread
+/// support show it as a reply to the root.
+fn thread_relation(root: &EventId) -> JsonValue {
+    json!({
+        "rel_type": "m.thread",
+        "event_id": root,
+        "is_falling_back": true,
+        "m.in_reply_to": { "event_id": root },
+    })
+}
+
+/// Prefix `content` with pills of the mentioned users, or `@room`, and
+/// return the matching `m.mentions` field
+fn mention(content: &mut MessageEventContent, mentions: &[String]) -> JsonValue {
+    let mut room = false;
+    let mut user_ids = Vec::new();
+    let mut plain = Vec::new();
+    let mut html = Vec::new();
+
+    for mention in mentions {
+        if mention == "@room" {
+            room = true;
+            plain.push(mention.to_string());
+            html.push(mention.to_string());
+            continue;
+        }
+        match UserId::try_from(mention.as_str()) {
+            Err(_) => warn!("Not mentioning invalid user ID '{}'", mention),
+            Ok(user_id) => {
+                plain.push(user_id.to_string());
+                html.push(format!(
+                    "<a href=\"https://matrix.to/#/{}\">{}</a>",
+                    user_id, user_id
+                ));
+                user_ids.push(user_id);
+            }
+        }
+    }
+
+    let text = match &mut content.msgtype {
+        MessageType::Text(text) => Some((&mut text.body, &mut text.formatted)),
+        MessageType::Notice(notice) => Some((&mut notice.body, &mut notice.formatted)),
+        _ => None,
+    };
+    if let Some((body, formatted)) = text {
+        if !plain.is_empty() {
+            *body = format!("{}\n{}", plain.join(" "), body)
+                .trim_end()
+                .to_string();
+        }
+        if let Some(formatted) = formatted {
+            if !html.is_empty() {
+                formatted.body = format!("<p>{}</p>{}", html.join(" "), formatted.body);
+            }
+        }
+    }
+
+    let mut field = json!({ "user_ids": user_ids });
+    if room {
+        field["room"] = json!(true);
+    }
+    field
+}
+
+/// Add fields the SDK has no types for yet to `content`
+fn with_fields(
+    content: MessageEventContent,
+    fields: BTreeMap<String, JsonValue>,
+) -> Result<AnyMessageEventContent, Error> {
+    let mut data: BTreeMap<String, JsonValue> =
+        serde_json::from_value(serde_json::to_value(content)?)?;
+    data.extend(fields);
+
+    Ok(AnyMessageEventContent::_Custom(CustomEventContent {
+        event_type: "m.room.message".to_string(),
+        data,
+    }))
+}
+
+/// Whether the device keys carry a valid signature by the self-signing key
+fn is_cross_signed(device_keys: &DeviceKeys, self_signing_key: &CrossSigningKey) -> bool {
+    let signatures = match device_keys.signatures.get(&device_keys.user_id) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    // The signed JSON is the canonical JSON without signatures and unsigned
+    let mut signed = match serde_json::to_value(device_keys) {
+        Ok(serde_json::Value::Object(v)) => v,
+        _ => return false,
+    };
+    signed.remove("signatures");
+    signed.remove("unsigned");
+    let signed = serde_json::Value::Object(signed).to_string();
+
+    let utility = OlmUtility::new();
+    self_signing_key.keys.iter().any(|(key_id, key)| {
+        DeviceKeyId::try_from(key_id.as_str())
+            .ok()
+            .and_then(|key_id| signatures.get(&key_id))
+            .map_or(false, |signature| {
+                utility
+                    .ed25519_verify(key, &signed, signature.clone())
+                    .unwrap_or(false)
+            })
+    })
+}
+
+/// The size and modification time of the last uploaded avatar file and the
+/// URI it got
+#[derive(Serialize, Deserialize)]
+struct UploadedAvatar {
+    size: u64,
+
+    /// Nanoseconds since the epoch
+    modified: u128,
+
+    uri: String,
+}
+
+/// Read a state file in `state_dir`, which is empty if it doesn't exist yet
+fn read_state<T: DeserializeOwned + Default>(path: &Path) -> Result<T, Error> {
+    match read_to_string(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+        Ok(raw) => Ok(serde_json::from_str(&raw)?),
+    }
+}
+
+/// Replace a state file in `state_dir` atomically
+async fn write_state<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let temporary = path.with_extension("json.tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec_pretty(value)?).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+/// Replace `{host}` by the hostname, so the same configuration names the bot
+/// after each host
+fn with_hostname(name: &str) -> String {
+    name.replace("{host}", &util::hostname())
+}
+
+/// Allowlist entries are either user IDs or server names
+fn is_allowed(allowlist: &[String], user_id: &UserId) -> bool {
+    allowlist
+        .iter()
+        .any(|entry| entry == user_id.as_str() || entry == user_id.server_name().as_str())
+}
+
+pub(crate) fn is_not_found(e: &HttpError) -> bool {
+    matches!(
+        e,
+        HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e)))
+            if e.kind == ErrorKind::NotFound
+    )
+}
+
 impl Matrix {
     pub fn new(
-        user: &str,
-        password: &str,
-        channel: &str,
-        message_template: &str,
+        matrix_config: &MatrixConfig,
         spooler: Receiver<Message>,
-        send_reporter: Sender<Option<Message>>,
-        verifier: UnboundedReceiver<Sas>,
+        send_reporter: Sender<Report>,
+        verifier: UnboundedReceiver<VerifyRequest>,
+        control: Sender<Control>,
+        key_store: KeyStore,
     ) -> Result<Self, Error> {
-        let mut iter = user.splitn(2, ':');
+        let mut iter = matrix_config.user.splitn(2, ':');
         let username = iter.next().ok_or(Error::InvalidUser)?;
         let server = iter.next().ok_or(Error::InvalidUser)?;
+        let user_id = UserId::try_from(format!("@{}:{}", username, server))
+            .map_err(|_| Error::InvalidUser)?;
 
-        let homeserver_url = resolve_well_known(server)?;
+        if matrix_config.password.is_none() && matrix_config.access_token.is_none() {
+            return Err(Error::MissingCredentials);
+        }
+        if matrix_config.access_token.is_some() && matrix_config.device_id.is_none() {
+            return Err(Error::MissingDeviceId);
+        }
+
+        let homeserver_url = match &matrix_config.homeserver_url {
+            Some(url) => url.to_string(),
+            None => format!("https://{}", server),
+        };
         let homeserver_url = Url::parse(&homeserver_url).map_err(|_| Error::InvalidHomeServer)?;
 
-        let config = ClientConfig::new().store_path("./");
+        let state_dir = Path::new(&matrix_config.state_dir);
+        create_dir_all(state_dir)?;
+
+        let config = ClientConfig::new().store_path(state_dir);
 
         let client = Client::new_with_config(homeserver_url, config)?;
 
         let mut tera = Tera::default();
-        tera.add_raw_template("", message_template)?;
+        tera.add_raw_templates(vec![
+            (HTML_TEMPLATE, &matrix_config.message_template),
+            (PLAIN_TEMPLATE, &matrix_config.plain_template),
+        ])?;
+
+        let provisioning_invites = match &matrix_config.room_provisioning {
+            Some(provisioning) => provisioning
+                .invite
+                .iter()
+                .map(|user| {
+                    UserId::try_from(user.as_str())
+                        .map_err(|_| Error::InvalidInvitee(user.to_string()))
+                })
+                .collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        let provisioned_rooms_path = state_dir.join(PROVISIONED_ROOMS_FILE);
+        let provisioned_rooms = read_state(&provisioned_rooms_path)?;
+
+        let blacklist_path = state_dir.join(BLACKLIST_FILE);
+        let blacklist = read_state(&blacklist_path)?;
+
+        let key_backup = matrix_config.key_backup.as_ref().map(|config| {
+            Arc::new(tokio::sync::Mutex::new(KeyBackup::new(
+                client.clone(),
+                config.clone(),
+                state_dir,
+            )))
+        });
+
+        let silences = Silences::default();
+        let acks = Acks::default();
 
         Ok(Matrix {
             client,
             username: username.to_string(),
-            password: password.to_string(),
-            channel: channel.to_string(),
+            server: server.to_string(),
+            homeserver_configured: matrix_config.homeserver_url.is_some(),
+            user_id,
+            password: matrix_config.password.clone(),
+            access_token: matrix_config.access_token.clone(),
+            device_id: matrix_config.device_id.clone(),
+            session_path: state_dir.join(SESSION_FILE),
+            channel: matrix_config.room.to_string(),
+            rooms: HashMap::new(),
+            invite_allowlist: matrix_config.invite_allowlist.clone(),
+            mentions: matrix_config.mentions.clone(),
+            msgtype: matrix_config.msgtype,
+            display_name: matrix_config.display_name.as_deref().map(with_hostname),
+            room_display_names: matrix_config
+                .room_display_names
+                .iter()
+                .map(|(room, name)| (room.to_string(), with_hostname(name)))
+                .collect(),
+            avatar: matrix_config.avatar.clone(),
+            avatar_state_path: state_dir.join(AVATAR_FILE),
+            cross_signing: matrix_config.cross_signing,
+            cross_signing_devices: matrix_config.cross_signing_devices.clone(),
+            trust_policy: matrix_config.trust_policy,
+            blacklist,
+            blacklist_path,
+            key_backup,
+            keys_changed: Arc::new(AtomicBool::new(false)),
+            room_provisioning: matrix_config.room_provisioning.clone(),
+            provisioning_invites,
+            provisioned_rooms,
+            provisioned_rooms_path,
+            commands: CommandHandler::new(
+                matrix_config.admins.clone(),
+                silences.clone(),
+                acks.clone(),
+                control,
+            ),
+            silences,
+            acks,
+            key_store,
             tera,
             spooler,
             send_reporter,
@@ -140,23 +624,339 @@ impl Matrix {
         })
     }
 
-    pub async fn login(self) -> Result<Self, Error> {
-        let device_id = util::hostname();
-        if self
-            .client
-            .login(
-                &self.username,
-                &self.password,
-                Some(&device_id),
-                Some(&device_id),
-            )
+    /// Log in, retrying with backoff until it succeeds. Meanwhile arriving
+    /// messages are handed back to the spool to be retried after the next
+    /// attempt. Returns false if the daemon is shutting down.
+    pub async fn connect(&mut self) -> bool {
+        let mut backoff = Backoff::new();
+
+        loop {
+            match self.login().await {
+                Ok(()) => {
+                    systemd::notify_status("Connected to Matrix".to_string()).await;
+                    self.update_profile().await;
+                    if self.cross_signing {
+                        if let Err(e) = self.bootstrap_cross_signing().await {
+                            warn!("Failed to bootstrap cross-signing: {}", e);
+                        }
+                    }
+                    if let Some(key_backup) = &self.key_backup {
+                        let mut key_backup = key_backup.lock().await;
+                        match key_backup.set_up().await {
+                            Ok(true) => self.keys_changed.store(true, Ordering::SeqCst),
+                            Ok(false) => {}
+                            Err(e) => warn!("Failed to set up key backup: {}", e),
+                        }
+                    }
+                    if self.trust_policy != TrustPolicy::VerifiedOnly {
+                        if let Err(e) = self.release_blacklist().await {
+                            warn!("Failed to release blacklisted devices: {}", e);
+                        }
+                    }
+                    return true;
+                }
+                Err(e) => {
+                    let delay = backoff.get_backoff().min(MAX_LOGIN_BACKOFF);
+                    error!("Matrix login failed, retrying in {}s: {}", delay, e);
+                    systemd::notify_status(format!("Degraded, spooling messages: {}", e)).await;
+
+                    let retry_at = Instant::now() + Duration::from_secs(delay);
+                    if !self.spool_until(retry_at).await {
+                        return false;
+                    }
+                    if delay < MAX_LOGIN_BACKOFF {
+                        backoff.backoff();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn spool_until(&mut self, deadline: Instant) -> bool {
+        loop {
+            tokio::select! {
+                next = self.spooler.recv() => {
+                    let message = match next {
+                        Some(v) => v,
+                        None => {
+                            debug!("Matrix shutting down");
+                            return false;
+                        }
+                    };
+                    let delay = deadline.saturating_duration_since(Instant::now());
+                    if self.send_reporter.send(Report::RetryAfter(message, delay)).await.is_err() {
+                        debug!("Matrix shutting down");
+                        return false;
+                    }
+                }
+                _ = sleep_until(deadline) => return true,
+            }
+        }
+    }
+
+    /// Restore the persisted session if there is one. Otherwise log in with
+    /// the configured access token or password, and persist the session for
+    /// the next start.
+    ///
+    /// Tokens are checked before handing them to the client, as each restore
+    /// opens the crypto store anew and every one after the first gets an
+    /// empty store, i.e. new device keys.
+    async fn login(&mut self) -> Result<(), Error> {
+        if self.client.logged_in().await {
+            debug!("Session was restored by an earlier attempt");
+            return Ok(());
+        }
+
+        if !self.homeserver_configured {
+            let homeserver_url = discover_homeserver(&self.server).await?;
+            self.client.set_homeserver(homeserver_url).await;
+        }
+
+        let persisted = self.read_session()?;
+        let device_id = persisted
+            .as_ref()
+            .map(|session| session.device_id.to_string())
+            .or_else(|| self.device_id.clone())
+            .unwrap_or_else(util::hostname);
+
+        if let Some(session) = persisted {
+            if self.is_token_valid(&session.access_token).await? {
+                debug!("Restoring session of device {}", session.device_id);
+                self.client.restore_login(session).await?;
+                return Ok(());
+            }
+            warn!(
+                "Persisted session of device {} is unusable",
+                session.device_id
+            );
+        }
+
+        let session = match (&self.password, &self.access_token) {
+            (Some(password), _) => {
+                let response = self
+                    .client
+                    .login(
+                        &self.username,
+                        password,
+                        Some(&device_id),
+                        Some(&util::hostname()),
+                    )
+                    .await
+                    .map_err(|_| Error::InvalidLogin)?;
+                Session {
+                    access_token: response.access_token,
+                    user_id: response.user_id,
+                    device_id: response.device_id,
+                }
+            }
+            (None, Some(access_token)) => {
+                if !self.is_token_valid(access_token).await? {
+                    return Err(Error::InvalidLogin);
+                }
+                let session = Session {
+                    access_token: access_token.to_string(),
+                    user_id: self.user_id.clone(),
+                    device_id: self.device_id.as_deref().unwrap_or(&device_id).into(),
+                };
+                self.client.restore_login(session.clone()).await?;
+                session
+            }
+            (None, None) => return Err(Error::MissingCredentials),
+        };
+
+        // The client is logged in now and must not be restored again, so
+        // this is no reason to retry
+        if let Err(e) = self.write_session(&session) {
+            warn!("Failed to persist session: {}", e);
+        }
+        info!("Logged in as device {}", session.device_id);
+
+        Ok(())
+    }
+
+    /// Ask the homeserver whether the access token is still valid, bypassing
+    /// the client. Other errors than a rejected token are returned, so a
+    /// network outage doesn't replace a working session.
+    async fn is_token_valid(&self, access_token: &str) -> Result<bool, Error> {
+        let url = format!(
+            "{}/_matrix/client/r0/account/whoami",
+            self.client
+                .homeserver()
+                .await
+                .as_str()
+                .trim_end_matches('/')
+        );
+        let response = reqwest::Client::new()
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
             .await
-            .is_err()
-        {
-            return Err(Error::InvalidLogin);
+            .map_err(Error::TokenCheck)?;
+        match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Ok(false),
+            _ => response
+                .error_for_status()
+                .map(|_| true)
+                .map_err(Error::TokenCheck),
+        }
+    }
+
+    /// Set the configured display names and avatar. Failures are only logged
+    /// as the bot works without them.
+    async fn update_profile(&mut self) {
+        if let Some(name) = &self.display_name {
+            let current = self.client.display_name().await.ok().flatten();
+            if current.as_ref() != Some(name) {
+                match self.client.set_display_name(Some(name)).await {
+                    Ok(()) => info!("Set display name to '{}'", name),
+                    Err(e) => warn!("Failed to set display name: {}", e),
+                }
+            }
         }
 
-        Ok(self)
+        if let Some(path) = self.avatar.clone() {
+            if let Err(e) = self.update_avatar(&path).await {
+                warn!("Failed to set avatar from {}: {}", path, e);
+            }
+        }
+
+        for (channel, name) in self.room_display_names.clone() {
+            if let Err(e) = self.set_room_display_name(&channel, &name).await {
+                warn!("Failed to set display name in {}: {}", channel, e);
+            }
+        }
+    }
+
+    /// Create and upload cross-signing keys unless the account has some
+    /// already. They are kept in the crypto store in `state_dir`.
+    async fn bootstrap_cross_signing(&self) -> Result<(), Error> {
+        if let Some(status) = self.client.cross_signing_status().await {
+            if status.has_master && status.has_self_signing {
+                debug!("Cross-signing keys are present");
+                return Ok(());
+            }
+        }
+
+        // The identity of the own user is only known locally after the first
+        // sync, so ask the server instead
+        let request = assign!(get_keys::Request::new(), {
+            device_keys: BTreeMap::from([(self.user_id.clone(), Vec::new())]),
+        });
+        let response = self.client.send(request, None).await?;
+        if response.master_keys.contains_key(&self.user_id) {
+            warn!(
+                "The account has cross-signing keys, but this device doesn't. \
+                 The device holding them signs this one."
+            );
+            return Ok(());
+        }
+
+        let password = self
+            .password
+            .as_deref()
+            .ok_or(Error::CrossSigningNeedsPassword)?;
+        if let Err(e) = self.client.bootstrap_cross_signing(None).await {
+            let response = match e.uiaa_response() {
+                None => return Err(e.into()),
+                Some(v) => v,
+            };
+            let auth = AuthData::Password(assign!(
+                Password::new(UserIdentifier::MatrixId(&self.username), password),
+                { session: response.session.as_deref() }
+            ));
+            self.client.bootstrap_cross_signing(Some(auth)).await?;
+        }
+
+        info!("Bootstrapped cross-signing");
+        Ok(())
+    }
+
+    /// Upload the avatar unless it is unchanged since the last upload and
+    /// still in use
+    async fn update_avatar(&self, path: &str) -> Result<(), Error> {
+        let metadata = metadata(path).map_err(Error::Avatar)?;
+        let size = metadata.len();
+        let modified = metadata
+            .modified()
+            .map_err(Error::Avatar)?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        let current = self.client.avatar_url().await?;
+        let uploaded: Option<UploadedAvatar> = read_to_string(&self.avatar_state_path)
+            .ok()
+            .and_then(|raw| serde_json::from_str(&raw).ok());
+        if let (Some(uploaded), Some(current)) = (uploaded, current) {
+            if uploaded.size == size
+                && uploaded.modified == modified
+                && uploaded.uri == current.as_str()
+            {
+                debug!("Avatar is up to date");
+                return Ok(());
+            }
+        }
+
+        let content = read(path).map_err(Error::Avatar)?;
+
+        let content_type = match Path::new(path).extension().and_then(|v| v.to_str()) {
+            Some("png") => mime::IMAGE_PNG,
+            Some("jpg") | Some("jpeg") => mime::IMAGE_JPEG,
+            Some("gif") => mime::IMAGE_GIF,
+            _ => mime::APPLICATION_OCTET_STREAM,
+        };
+        let uri = self
+            .client
+            .upload(&content_type, &mut content.as_slice())
+            .await?
+            .content_uri;
+        self.client.set_avatar_url(Some(&uri)).await?;
+        info!("Set avatar to {}", uri);
+
+        let uploaded = UploadedAvatar {
+            size,
+            modified,
+            uri: uri.to_string(),
+        };
+        write(&self.avatar_state_path, serde_json::to_vec(&uploaded)?)?;
+        Ok(())
+    }
+
+    /// Override the display name in a single room by changing the bot's
+    /// membership event there
+    async fn set_room_display_name(&mut self, channel: &str, name: &str) -> Result<(), Error> {
+        let room = self.resolve_channel(channel).await?;
+        let avatar_url = self.client.avatar_url().await?;
+        let content = assign!(MemberEventContent::new(MembershipState::Join), {
+            displayname: Some(name.to_string()),
+            avatar_url,
+        });
+        let content = AnyStateEventContent::RoomMember(content);
+        let request = send_state_event::Request::new(&room, self.user_id.as_str(), &content);
+        self.client.send(request, None).await?;
+        debug!("Set display name in {} to '{}'", room, name);
+        Ok(())
+    }
+
+    fn read_session(&self) -> Result<Option<Session>, Error> {
+        match read_to_string(&self.session_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+            Ok(raw) => Ok(Some(
+                serde_json::from_str(&raw).map_err(Error::InvalidSession)?,
+            )),
+        }
+    }
+
+    fn write_session(&self, session: &Session) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.session_path)?;
+        file.write_all(&serde_json::to_vec(session)?)?;
+        Ok(())
     }
 
     pub async fn run(&mut self) {
@@ -168,15 +968,30 @@ impl Matrix {
         let mut verifier = Verifier {
             local_receiver: self.verifier.take().unwrap(),
             remote_receiver: from_matrix,
+            waiting: Vec::new(),
+            pending: BTreeMap::new(),
         };
 
         tokio::spawn(async move { verifier.run().await });
 
+        let invite_allowlist = self.invite_allowlist.clone();
+        let commands = self.commands.clone();
+        let acks = self.acks.clone();
+        let cross_signing = self.cross_signing;
+        let cross_signing_devices = self.cross_signing_devices.clone();
+        let user_id = self.user_id.clone();
+
         tokio::spawn(async move {
             let client = client_for_syncer;
             let client = &client;
             let to_verifier = to_verifier;
             let to_verifier_ref = &to_verifier;
+            let invite_allowlist = &invite_allowlist;
+            let commands = &commands;
+            let acks = &acks;
+            let user_id = &user_id;
+            let cross_signing_devices = &cross_signing_devices;
+            let signed_devices = &std::sync::Mutex::new(HashSet::new());
             let settings = SyncSettings::new().timeout(Duration::from_secs(300));
 
             let initial_call_flag = Arc::new(AtomicBool::from(true));
@@ -195,19 +1010,47 @@ impl Matrix {
                         Self::handle_to_device_event(client, event, to_verifier_ref).await;
                     }
 
+                    for (room_id, room_info) in response.rooms.invite {
+                        Self::handle_invite(
+                            client,
+                            &room_id,
+                            &room_info.invite_state.events,
+                            invite_allowlist.as_deref(),
+                        )
+                        .await;
+                    }
+
                     if !initial_call_flag_ref.load(Ordering::SeqCst) {
-                        for (_room_id, room_info) in response.rooms.join {
+                        for (room_id, room_info) in response.rooms.join {
                             for event in room_info
                                 .timeline
                                 .events
                                 .iter()
                                 .filter_map(|e| e.event.deserialize().ok())
                             {
-                                Self::handle_room_event(client, event, to_verifier_ref).await;
+                                Self::handle_room_event(
+                                    client,
+                                    &room_id,
+                                    event,
+                                    to_verifier_ref,
+                                    commands,
+                                    acks,
+                                )
+                                .await;
                             }
                         }
                     }
 
+                    if cross_signing {
+                        Self::sign_own_devices(
+                            client,
+                            user_id,
+                            cross_signing_devices,
+                            signed_devices,
+                        )
+                        .await;
+                    }
+
                     initial_call_flag_ref.store(false, Ordering::SeqCst);
 
                     LoopCtrl::Continue
@@ -215,17 +1058,54 @@ impl Matrix {
                 .await
         });
 
+        // Exporting the room keys takes a while, so it must not hold up
+        // sending
+        if let Some(key_backup) = self.key_backup.clone() {
+            let keys_changed = self.keys_changed.clone();
+            tokio::spawn(async move {
+                let mut ticker = interval(key_backup.lock().await.interval());
+                loop {
+                    ticker.tick().await;
+                    if !keys_changed.swap(false, Ordering::SeqCst) {
+                        continue;
+                    }
+                    if let Err(e) = key_backup.lock().await.back_up_keys().await {
+                        warn!("Failed to back up room keys: {}", e);
+                        keys_changed.store(true, Ordering::SeqCst);
+                    }
+                }
+            });
+        }
+
         loop {
             tokio::select! {
                 next = self.spooler.recv() => {
                    if let Some(message) = next {
-                        debug!("Sending message");
-                        if self.send_message(&message).await.is_err() {
-                            if self.send_reporter.send(Some(message)).await.is_err() {
+                        if self.silences.is_silenced(&message) {
+                            info!("Dropping silenced message '{}'", message.title);
+                            if self.send_reporter.send(Report::Sent).await.is_err() {
                                 debug!("Matrix shutting down");
                                 return;
                             }
-                        } else if self.send_reporter.send(None).await.is_err() {
+                            continue;
+                        }
+
+                        debug!("Sending message");
+                        let report = match self.send_message(&message).await {
+                            Err(e) if e.is_permanent() => {
+                                error!("Dropping message as it cannot be sent: {}", e);
+                                Report::Sent
+                            }
+                            Err(e) => {
+                                warn!("Error while sending: {}", e);
+                                Report::Failed(message)
+                            }
+                            Ok(()) => {
+                                self.keys_changed.store(true, Ordering::SeqCst);
+                                Report::Sent
+                            }
+                        };
+                        if self.send_reporter.send(report).await.is_err() {
                             debug!("Matrix shutting down");
                             return;
                         }
@@ -238,41 +1118,622 @@ impl Matrix {
         }
     }
 
-    pub async fn send_message(&self, message: &Message) -> Result<(), ()> {
-        let channel = message
-            .channel
-            .clone()
-            .unwrap_or_else(|| self.channel.to_string());
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), Error> {
+        let channel = match &message.channel {
+            Some(channel) => channel.clone(),
+            None => self
+                .provisioned_room(message)
+                .await?
+                .unwrap_or_else(|| self.channel.to_string()),
+        };
+
+        let room = self.resolve_channel(&channel).await?;
+        let joined_room = self.wait_for_joined_room(&room).await?;
+        self.apply_trust_policy(&joined_room).await?;
+
+        let earlier = message
+            .key
+            .as_deref()
+            .and_then(|key| self.key_store.get(key))
+            .filter(|sent| sent.room == room.as_str())
+            .cloned();
+
+        match (&message.key, earlier) {
+            (Some(key), Some(sent)) => self.update_message(&room, key, sent, message).await?,
+            _ => {
+                let mut content = self.render(message)?;
+                let mut fields = BTreeMap::new();
+                fields.insert(
+                    "m.mentions".to_string(),
+                    mention(&mut content, &self.mentions.for_message(message)),
+                );
+                if let Some(thread) = &message.thread {
+                    let root = self.thread_root(&room, &channel, thread, message).await?;
+                    fields.insert("m.relates_to".to_string(), thread_relation(&root));
+                }
+                let content = with_fields(content, fields)?;
+                let response = self.client.room_send(&room, content, None).await?;
+                self.acks
+                    .record_sent(response.event_id.as_str(), room.as_str(), &message.title);
+                if let Some(key) = &message.key {
+                    self.key_store
+                        .insert(
+                            key,
+                            Sent::new(room.as_str(), response.event_id.as_str(), message),
+                        )
+                        .await;
+                }
+            }
+        }
+
+        if message.attachments.is_empty() {
+            return Ok(());
+        }
+
+        // Failures are not retried as that would post the message again
+        for attachment in &message.attachments {
+            let content_type = attachment
+                .content_type
+                .parse::<mime::Mime>()
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            if let Err(e) = joined_room
+                .send_attachment(
+                    &attachment.name,
+                    &content_type,
+                    &mut attachment.content.as_slice(),
+                    None,
+                )
+                .await
+            {
+                warn!(
+                    "Failed to upload attachment '{}': {:#?}",
+                    attachment.name, e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait until the room shows up as joined in the sync, e.g. after it was
+    /// just created. Until then the client doesn't know whether the room is
+    /// encrypted and sends messages there unencrypted.
+    async fn wait_for_joined_room(&self, room: &RoomId) -> Result<Joined, Error> {
+        let deadline = Instant::now() + JOIN_TIMEOUT;
+        loop {
+            if let Some(joined_room) = self.client.get_joined_room(room) {
+                return Ok(joined_room);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::NotJoined(room.to_string()));
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Exclude unverified devices in encrypted rooms from the room keys by
+    /// blacklisting them, or refuse to send there, depending on the policy
+    async fn apply_trust_policy(&mut self, joined_room: &Joined) -> Result<(), Error> {
+        if self.trust_policy == TrustPolicy::All || !joined_room.is_encrypted() {
+            return Ok(());
+        }
+
+        let own_device = self.client.device_id().await;
+        let blacklist_before = self.blacklist.clone();
+        let mut unverified = Vec::new();
+        // The members the room keys are shared with
+        for member in joined_room.active_members().await? {
+            let devices = self
+                .client
+                .get_user_devices(member.user_id())
+                .await
+                .map_err(matrix_sdk::Error::from)?;
+            for device in devices.devices() {
+                if own_device.as_deref() == Some(device.device_id()) || device.deleted() {
+                    continue;
+                }
+
+                let entry = (device.user_id().to_string(), device.device_id().to_string());
+                match self.trust_policy {
+                    TrustPolicy::Block if !device.verified() => {
+                        unverified.push(format!("{} {}", device.user_id(), device.device_id()));
+                    }
+                    TrustPolicy::VerifiedOnly if device.is_blacklisted() && device.verified() => {
+                        if !self.blacklist.remove(&entry) {
+                            continue;
+                        }
+                        info!(
+                            "Sending to {} {} again as it is verified now",
+                            device.user_id(),
+                            device.device_id()
+                        );
+                        device
+                            .set_local_trust(LocalTrust::Unset)
+                            .await
+                            .map_err(matrix_sdk::Error::from)?;
+                    }
+                    TrustPolicy::VerifiedOnly if !device.is_blacklisted() && !device.verified() => {
+                        info!(
+                            "Not sending to unverified device {} {}",
+                            device.user_id(),
+                            device.device_id()
+                        );
+                        device
+                            .set_local_trust(LocalTrust::BlackListed)
+                            .await
+                            .map_err(matrix_sdk::Error::from)?;
+                        self.blacklist.insert(entry);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if self.blacklist != blacklist_before {
+            write_state(&self.blacklist_path, &self.blacklist).await?;
+        }
+
+        if unverified.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::UnverifiedDevices(unverified.join(", ")))
+        }
+    }
+
+    /// Lift the blacklisting of the devices blacklisted by `verified_only`,
+    /// once another policy is configured
+    async fn release_blacklist(&mut self) -> Result<(), Error> {
+        if self.blacklist.is_empty() {
+            return Ok(());
+        }
+
+        for (user_id, device_id) in &self.blacklist {
+            let user_id = match UserId::try_from(user_id.as_str()) {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let device = self
+                .client
+                .get_device(&user_id, device_id.as_str().into())
+                .await
+                .map_err(matrix_sdk::Error::from)?;
+            if let Some(device) = device.filter(|device| device.is_blacklisted()) {
+                info!("Sending to {} {} again", user_id, device_id);
+                device
+                    .set_local_trust(LocalTrust::Unset)
+                    .await
+                    .map_err(matrix_sdk::Error::from)?;
+            }
+        }
+
+        self.blacklist.clear();
+        write_state(&self.blacklist_path, &self.blacklist).await
+    }
+
+    /// Find the room ID of a channel, which is either a room ID, a room alias
+    /// or a user ID to send a direct message to
+    async fn resolve_channel(&mut self, channel: &str) -> Result<RoomId, Error> {
+        if let Ok(room_id) = RoomId::try_from(channel) {
+            return Ok(room_id);
+        }
+
+        if let Some(room_id) = self.rooms.get(channel) {
+            return Ok(room_id.clone());
+        }
+
+        let room_id = if let Ok(alias) = RoomAliasId::try_from(channel) {
+            self.resolve_alias(&alias).await?
+        } else if let Ok(user_id) = UserId::try_from(channel) {
+            self.find_direct_room(&user_id).await?
+        } else {
+            return Err(Error::InvalidChannel(channel.to_string()));
+        };
+
+        debug!("Resolved {} to {}", channel, room_id);
+        self.rooms.insert(channel.to_string(), room_id.clone());
+        Ok(room_id)
+    }
+
+    async fn resolve_alias(&self, alias: &RoomAliasId) -> Result<RoomId, Error> {
+        match self.client.send(get_alias::Request::new(alias), None).await {
+            Ok(response) => Ok(response.room_id),
+            Err(e) if is_not_found(&e) => Err(Error::UnknownAlias(alias.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reuse the direct message room with `user_id` or create an encrypted
+    /// one
+    async fn find_direct_room(&self, user_id: &UserId) -> Result<RoomId, Error> {
+        if let Some(room) = self
+            .client
+            .joined_rooms()
+            .into_iter()
+            .find(|room| room.direct_target().as_ref() == Some(user_id))
+        {
+            return Ok(room.room_id().clone());
+        }
+
+        info!("Creating direct message room with {}", user_id);
+        let encryption = Raw::from_json(to_raw_value(&json!({
+            "type": "m.room.encryption",
+            "state_key": "",
+            "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+        }))?);
+        let invite = [user_id.clone()];
+        let initial_state = [encryption];
+        let request = assign!(create_room::Request::new(), {
+            invite: &invite,
+            initial_state: &initial_state,
+            is_direct: true,
+            preset: Some(RoomPreset::TrustedPrivateChat),
+        });
+        let room_id = self.client.create_room(request).await?.room_id;
+
+        if let Err(e) = self.mark_as_direct(user_id, &room_id).await {
+            warn!("Failed to mark {} as direct message room: {}", room_id, e);
+        }
+
+        Ok(room_id)
+    }
+
+    /// The room for messages without a channel, created on first use for
+    /// each host or value of the configured field. Messages without the
+    /// field go to the default room.
+    async fn provisioned_room(&mut self, message: &Message) -> Result<Option<String>, Error> {
+        let provisioning = match &self.room_provisioning {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let value = match &provisioning.field {
+            Some(field) => match message.fields.get(field) {
+                Some(value) => value.to_string(),
+                None => return Ok(None),
+            },
+            None => util::hostname(),
+        };
+        if let Some(room_id) = self.provisioned_rooms.get(&value) {
+            return Ok(Some(room_id.to_string()));
+        }
+
+        let name = provisioning.name.replace("{value}", &value);
+        // A space that doesn't exist must not keep the alerts from going out
+        let space = match provisioning.space.clone() {
+            Some(space) => match self.resolve_channel(&space).await {
+                Ok(v) => Some(v),
+                Err(e) if e.is_permanent() => {
+                    error!("Creating room without space {}: {}", space, e);
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+
+        info!("Creating room '{}' for {}", name, value);
+        let mut initial_state = vec![Raw::from_json(to_raw_value(&json!({
+            "type": "m.room.encryption",
+            "state_key": "",
+            "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+        }))?)];
+        if let Some(space) = &space {
+            initial_state.push(Raw::from_json(to_raw_value(&json!({
+                "type": "m.space.parent",
+                "state_key": space.as_str(),
+                "content": { "via": [&self.server], "canonical": true },
+            }))?));
+        }
+        let request = assign!(create_room::Request::new(), {
+            invite: &self.provisioning_invites,
+            initial_state: &initial_state,
+            name: <&RoomName>::try_from(name.as_str()).ok(),
+            preset: Some(RoomPreset::PrivateChat),
+        });
+        let room_id = self.client.create_room(request).await?.room_id;
+
+        // Store the room first, so a failure below does not create another
+        self.provisioned_rooms
+            .insert(value, room_id.as_str().to_string());
+        write_state(&self.provisioned_rooms_path, &self.provisioned_rooms).await?;
+
+        if let Some(space) = &space {
+            if let Err(e) = self.add_to_space(space, &room_id).await {
+                warn!("Failed to add {} to space {}: {}", room_id, space, e);
+            }
+        }
+
+        Ok(Some(room_id.as_str().to_string()))
+    }
+
+    /// Make the room a child of the space. This requires permission to send
+    /// state events in the space.
+    async fn add_to_space(&self, space: &RoomId, room_id: &RoomId) -> Result<(), Error> {
+        let content = Raw::from_json(to_raw_value(&json!({ "via": [&self.server] }))?);
+        let request =
+            send_state_event::Request::new_raw(space, "m.space.child", room_id.as_str(), content);
+        self.client.send(request, None).await?;
+        Ok(())
+    }
+
+    /// Add the room to the `m.direct` account data so it is found again
+    /// after a restart
+    async fn mark_as_direct(&self, user_id: &UserId, room_id: &RoomId) -> Result<(), Error> {
+        let own_user_id = self.client.user_id().await.ok_or(Error::InvalidLogin)?;
+
+        let mut direct: BTreeMap<String, Vec<String>> = match self
+            .client
+            .send(
+                get_global_account_data::Request::new(&own_user_id, "m.direct"),
+                None,
+            )
+            .await
+        {
+            Ok(response) => response.account_data.deserialize_as()?,
+            Err(e) if is_not_found(&e) => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        direct
+            .entry(user_id.to_string())
+            .or_default()
+            .push(room_id.to_string());
+
+        let data = to_raw_value(&direct)?;
+        self.client
+            .send(
+                set_global_account_data::Request::new(&data, "m.direct", &own_user_id),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Replace the earlier message sent with `key` by the new state and the
+    /// history of earlier states
+    async fn update_message(
+        &mut self,
+        room: &RoomId,
+        key: &str,
+        mut sent: Sent,
+        message: &Message,
+    ) -> Result<(), Error> {
+        let event_id = EventId::try_from(sent.id.as_str())
+            .map_err(|_| Error::InvalidKeyedEvent(key.to_string()))?;
+
+        let new_content = self.render(&sent.with_history(message))?;
+        let mut content = new_content.clone();
+        content.relates_to = Some(Relation::Replacement(Replacement::new(
+            event_id,
+            Box::new(new_content),
+        )));
+
+        self.client
+            .room_send(room, AnyMessageEventContent::RoomMessage(content), None)
+            .await?;
+        debug!("Updated message with key '{}'", key);
+
+        sent.push(message);
+        self.key_store.insert(key, sent).await;
+        Ok(())
+    }
+
+    /// The root event of today's thread, which is posted on first use
+    async fn thread_root(
+        &mut self,
+        room: &RoomId,
+        channel: &str,
+        thread: &str,
+        message: &Message,
+    ) -> Result<EventId, Error> {
+        if let Some(root) = self
+            .key_store
+            .thread(channel, thread)
+            .and_then(|sent| EventId::try_from(sent.id.as_str()).ok())
+        {
+            return Ok(root);
+        }
+
+        debug!("Starting thread '{}' in {}", thread, room);
+        let content = MessageEventContent::notice_plain(key_store::thread_title(thread));
+        let root = self
+            .client
+            .room_send(room, AnyMessageEventContent::RoomMessage(content), None)
+            .await?
+            .event_id;
+        self.key_store
+            .insert_thread(
+                channel,
+                thread,
+                Sent::new(room.as_str(), root.as_str(), message),
+            )
+            .await;
+        Ok(root)
+    }
+
+    fn render(&self, message: &Message) -> Result<MessageEventContent, Error> {
+        let context = message.template_context();
+        let html = self.tera.render(HTML_TEMPLATE, &context)?;
+        let plain = self.tera.render(PLAIN_TEMPLATE, &context)?;
 
-        let room = TryFrom::try_from(channel).map_err(|_| ())?;
+        Ok(match self.msgtype {
+            MsgType::Text => MessageEventContent::text_html(plain.trim(), html),
+            MsgType::Notice => MessageEventContent::notice_html(plain.trim(), html),
+        })
+    }
 
-        let html = self.render(message).map_err(|_| ())?;
+    /// Sign the approved devices of the bot's account which are not signed by
+    /// the current self-signing key yet, if this device holds it. Each device
+    /// is checked once.
+    async fn sign_own_devices(
+        client: &Client,
+        user_id: &UserId,
+        approved: &[String],
+        checked: &std::sync::Mutex<HashSet<String>>,
+    ) {
+        match client.cross_signing_status().await {
+            Some(status) if status.has_self_signing => {}
+            _ => return,
+        }
+
+        let devices = match client.get_user_devices(user_id).await {
+            Err(e) => {
+                warn!("Failed to get own devices: {}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+        let own_device = client.device_id().await;
+        let unchecked: Vec<_> = devices
+            .devices()
+            .filter(|device| {
+                let device_id = device.device_id().as_str();
+                own_device.as_deref() != Some(device.device_id())
+                    && !device.deleted()
+                    && approved.iter().any(|approved| approved == device_id)
+                    && !checked.lock().unwrap().contains(device_id)
+            })
+            .collect();
+        if unchecked.is_empty() {
+            return;
+        }
 
-        match self.client.room_send(&room, html, None).await {
-            Ok(_) => Ok(()),
+        // The self-signing key and the device keys as the server has them
+        let request = assign!(get_keys::Request::new(), {
+            device_keys: BTreeMap::from([(user_id.clone(), Vec::new())]),
+        });
+        let mut response = match client.send(request, None).await {
             Err(e) => {
-                warn!("Error while sending: {:#?}", e);
-                Err(())
+                warn!("Failed to get own cross-signing keys: {}", e);
+                return;
+            }
+            Ok(v) => v,
+        };
+        let self_signing_key = match response.self_signing_keys.remove(user_id) {
+            Some(v) => v,
+            None => return,
+        };
+        let device_keys = response.device_keys.remove(user_id).unwrap_or_default();
+
+        for device in unchecked {
+            let signed = device_keys
+                .get(device.device_id())
+                .map_or(false, |keys| is_cross_signed(keys, &self_signing_key));
+            checked
+                .lock()
+                .unwrap()
+                .insert(device.device_id().to_string());
+            if signed {
+                continue;
+            }
+            match device.verify().await {
+                Ok(()) => info!("Signed own device {}", device.device_id()),
+                Err(e) => warn!("Failed to sign own device {}: {}", device.device_id(), e),
             }
         }
     }
 
-    fn render(&self, message: &Message) -> Result<AnyMessageEventContent, ()> {
-        let mut context = Context::default();
-        context.insert("m", message);
-        context.insert("level_color", &String::from(message.level.clone()));
+    /// Join rooms the bot is invited to by users on the allowlist and
+    /// reject all other invites. Without an allowlist every invite is joined.
+    async fn handle_invite(
+        client: &Client,
+        room_id: &RoomId,
+        invite_state: &[Raw<AnyStrippedStateEvent>],
+        allowlist: Option<&[String]>,
+    ) {
+        let room = match client.get_invited_room(room_id) {
+            Some(v) => v,
+            None => {
+                debug!("Invite to {} is already handled", room_id);
+                return;
+            }
+        };
 
-        let html = self.tera.render("", &context).map_err(|_| ())?;
+        let own_user_id = match client.user_id().await {
+            Some(v) => v,
+            None => return,
+        };
+        let inviter = invite_state
+            .iter()
+            .filter_map(|e| e.deserialize().ok())
+            .find_map(|e| match e {
+                AnyStrippedStateEvent::RoomMember(member)
+                    if member.state_key == own_user_id.as_str() =>
+                {
+                    Some(member.sender)
+                }
+                _ => None,
+            });
+
+        match inviter {
+            Some(inviter) if allowlist.map_or(true, |v| is_allowed(v, &inviter)) => {
+                info!("Accepting invite to {} from {}", room_id, inviter);
+                if let Err(e) = room.accept_invitation().await {
+                    warn!("Failed to join {}: {}", room_id, e);
+                }
+            }
+            inviter => {
+                warn!(
+                    "Rejecting invite to {} from {} not on the allowlist",
+                    room_id,
+                    inviter.map_or("unknown user".to_string(), |v| v.to_string())
+                );
+                if let Err(e) = room.reject_invitation().await {
+                    warn!("Failed to reject invite to {}: {}", room_id, e);
+                }
+            }
+        }
+    }
 
-        Ok(AnyMessageEventContent::RoomMessage(
-            MessageEventContent::text_html("", html),
-        ))
+    /// Record the acknowledgement and post or update the note listing who
+    /// acknowledged the alert
+    async fn acknowledge(
+        client: &Client,
+        room_id: &RoomId,
+        event_id: &EventId,
+        user_id: &UserId,
+        acks: &Acks,
+    ) {
+        let alert = match acks.ack(room_id.as_str(), event_id.as_str(), user_id.as_str()) {
+            Some(v) => v,
+            None => return,
+        };
+        info!("'{}' acknowledged by {}", alert.title, user_id);
+
+        let text = format!("Acknowledged by {}", alert.acked_by.join(", "));
+        let mut content = MessageEventContent::notice_plain(&text);
+        let note = alert.note.as_deref().map(EventId::try_from);
+        content.relates_to = Some(match note {
+            Some(Ok(note)) => {
+                content.msgtype =
+                    MessageType::Notice(NoticeMessageEventContent::plain(format!("* {}", text)));
+                Relation::Replacement(Replacement::new(
+                    note,
+                    Box::new(MessageEventContent::notice_plain(text)),
+                ))
+            }
+            _ => Relation::Reply {
+                in_reply_to: InReplyTo::new(event_id.clone()),
+            },
+        });
+
+        match client
+            .room_send(room_id, AnyMessageEventContent::RoomMessage(content), None)
+            .await
+        {
+            Ok(response) if alert.note.is_none() => acks.set_note(
+                room_id.as_str(),
+                event_id.as_str(),
+                response.event_id.as_str(),
+            ),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to post acknowledgement: {}", e),
+        }
     }
 
     async fn handle_to_device_event(
         client: &Client,
         event: AnyToDeviceEvent,
-        channel: &UnboundedSender<RemoteSas>,
+        channel: &UnboundedSender<(String, RemoteSas)>,
     ) {
         match event {
             AnyToDeviceEvent::KeyVerificationRequest(e) => {
@@ -304,10 +1765,14 @@ impl Matrix {
                             &sas.other_device().device_id()
                         );
                         sas.accept_with_settings(AcceptSettings::with_allowed_methods(vec![
+                            ShortAuthenticationString::Emoji,
                             ShortAuthenticationString::Decimal,
                         ]))
                         .await
                         .unwrap();
+                        if let Err(e) = channel.send((e.content.transaction_id.to_string(), sas)) {
+                            warn!("Failed to send to verifier: {}", e);
+                        }
                     }
                     v => debug!("Unknown variant of KeyVerificationStart: {:#?}", v),
                 }
@@ -320,7 +1785,7 @@ impl Matrix {
                     .await
                 {
                     Some(Verification::SasV1(sas)) => {
-                        if let Err(e) = channel.send(sas) {
+                        if let Err(e) = channel.send((e.content.transaction_id.to_string(), sas)) {
                             warn!("Failed to send to verifier: {}", e);
                         }
                     }
@@ -352,8 +1817,11 @@ impl Matrix {
 
     async fn handle_room_event(
         client: &Client,
+        room_id: &RoomId,
         event: AnySyncRoomEvent,
-        channel: &UnboundedSender<RemoteSas>,
+        channel: &UnboundedSender<(String, RemoteSas)>,
+        commands: &CommandHandler,
+        acks: &Acks,
     ) {
         if let AnySyncRoomEvent::Message(event) = event {
             match event {
@@ -373,10 +1841,33 @@ impl Matrix {
                             }
                             _ => debug!("No verification request found"),
                         }
+                    } else if let MessageType::Text(text) = &m.content.msgtype {
+                        if let Some(reply) = commands.handle(m.sender.as_str(), &text.body).await {
+                            let reply = AnyMessageEventContent::RoomMessage(
+                                MessageEventContent::notice_plain(reply),
+                            );
+                            if let Err(e) = client.room_send(room_id, reply, None).await {
+                                warn!("Failed to reply to command: {}", e);
+                            }
+                        }
                     } else {
                         debug!("Got unknown room message {:#?}", m);
                     }
                 }
+                AnySyncMessageEvent::Reaction(e) => {
+                    if acks::is_ack_emoji(&e.content.relates_to.emoji)
+                        && client.user_id().await.as_ref() != Some(&e.sender)
+                    {
+                        Self::acknowledge(
+                            client,
+                            room_id,
+                            &e.content.relates_to.event_id,
+                            &e.sender,
+                            acks,
+                        )
+                        .await;
+                    }
+                }
                 AnySyncMessageEvent::KeyVerificationKey(e) => {
                     debug!("SyncRoom Verification key obtained");
                     match client
@@ -384,7 +1875,8 @@ impl Matrix {
                         .await
                     {
                         Some(Verification::SasV1(sas)) => {
-                            if let Err(e) = channel.send(sas) {
+                            let id = e.content.relates_to.event_id.to_string();
+                            if let Err(e) = channel.send((id, sas)) {
                                 warn!("Failed to send to verifier: {}", e);
                             }
                         }
@@ -415,61 +1907,201 @@ impl Matrix {
     }
 }
 
+/// A verification started by another device
+struct PendingVerification {
+    sas: RemoteSas,
+
+    since: Instant,
+
+    /// Whether it was shown to `alert --verify` and awaits an answer
+    shown: bool,
+}
+
+/// Tracks verifications started by other devices by their transaction ID
+/// and pairs them with calls of `alert --verify`
 struct Verifier {
-    local_receiver: UnboundedReceiver<Sas>,
+    local_receiver: UnboundedReceiver<VerifyRequest>,
+
+    remote_receiver: UnboundedReceiver<(String, RemoteSas)>,
+
+    /// Calls of `alert --verify` waiting for a verification, optionally a
+    /// specific one
+    waiting: Vec<(Option<String>, oneshot::Sender<LocalVerification>)>,
 
-    remote_receiver: UnboundedReceiver<RemoteSas>,
+    pending: BTreeMap<String, PendingVerification>,
 }
 
 impl Verifier {
     async fn run(&mut self) {
+        let mut ticker = interval(VERIFICATION_CHECK_INTERVAL);
+
         loop {
-            match tokio::join!(self.local_receiver.recv(), self.remote_receiver.recv()) {
-                (Some(local_sas), Some(remote_sas)) => {
-                    self.check_equality(local_sas, remote_sas).await;
+            tokio::select! {
+                local = self.local_receiver.recv() => match local {
+                    Some(request) => self.handle_request(request),
+                    None => break,
+                },
+                remote = self.remote_receiver.recv() => match remote {
+                    Some((id, sas)) => self.track(id, sas),
+                    None => break,
+                },
+                _ = ticker.tick() => self.expire(),
+            }
+            self.offer();
+        }
+    }
+
+    fn handle_request(&mut self, request: VerifyRequest) {
+        self.expire();
+        match request {
+            VerifyRequest::Show { id, reply } => self.waiting.push((id, reply)),
+            VerifyRequest::List(reply) => {
+                let verifications = self
+                    .pending
+                    .iter()
+                    .map(|(id, pending)| describe(id, &pending.sas))
+                    .collect();
+                if reply.send(verifications).is_err() {
+                    debug!("Verification list requester went away");
                 }
-                (None, None) => break,
-                (_, None) => {
-                    warn!("Received no remote SAS");
+            }
+            VerifyRequest::Cancel { id, reply } => {
+                let pending = self.pending.remove(&id);
+                if let Some(pending) = &pending {
+                    info!("Cancelling verification {} as requested", id);
+                    tokio::spawn(Self::conclude(pending.sas.clone(), false));
                 }
-                (None, _) => {
-                    warn!("Received no local SAS");
+                if reply.send(pending.is_some()).is_err() {
+                    debug!("Verification cancel requester went away");
                 }
             }
+            VerifyRequest::Answer(confirmation) => match self.pending.remove(&confirmation.id) {
+                Some(pending) if pending.shown => {
+                    tokio::spawn(Self::conclude(pending.sas, confirmation.confirmed));
+                }
+                Some(pending) => {
+                    warn!(
+                        "Ignoring answer to verification {} which was not shown",
+                        confirmation.id
+                    );
+                    self.pending.insert(confirmation.id, pending);
+                }
+                None => warn!(
+                    "Ignoring answer to unknown or expired verification {}",
+                    confirmation.id
+                ),
+            },
         }
     }
 
-    async fn check_equality(&self, local_sas: Sas, remote_sas: RemoteSas) {
-        let remote = match remote_sas.decimals() {
+    fn track(&mut self, id: String, sas: RemoteSas) {
+        if sas.is_done() || sas.is_cancelled() {
+            self.pending.remove(&id);
+            return;
+        }
+
+        match self.pending.get_mut(&id) {
+            Some(pending) => pending.sas = sas,
             None => {
-                warn!("Remote SAS contains no decimals");
-                return;
+                info!(
+                    "Verification {} with {} {} is waiting for alert --verify",
+                    id,
+                    sas.other_device().user_id(),
+                    sas.other_device().device_id()
+                );
+                self.pending.insert(
+                    id,
+                    PendingVerification {
+                        sas,
+                        since: Instant::now(),
+                        shown: false,
+                    },
+                );
             }
-            Some(v) => v,
-        };
+        }
+    }
 
-        let remote = format!("{},{},{}", remote.0, remote.1, remote.2);
+    /// Forget finished verifications and cancel those taking too long
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.pending.retain(|id, pending| {
+            if pending.sas.is_done() || pending.sas.is_cancelled() {
+                debug!("Forgetting finished verification {}", id);
+                return false;
+            }
+            if now.duration_since(pending.since) > VERIFICATION_TIMEOUT {
+                warn!("Verification {} timed out", id);
+                tokio::spawn(Self::conclude(pending.sas.clone(), false));
+                return false;
+            }
+            true
+        });
+        self.waiting.retain(|(_, reply)| !reply.is_closed());
+    }
 
-        debug!("Remote: '{}'", remote);
-        debug!("Local: '{}'", local_sas.input);
+    /// Show presentable verifications to the waiting calls of `alert --verify`
+    fn offer(&mut self) {
+        let waiting = std::mem::take(&mut self.waiting);
+        for (wanted, reply) in waiting {
+            let candidate = self.pending.iter_mut().find(|(id, pending)| {
+                pending.sas.can_be_presented()
+                    && match &wanted {
+                        Some(wanted) => wanted == *id,
+                        None => !pending.shown,
+                    }
+            });
 
-        if local_sas.input == remote {
-            info!("Verification successful");
-            if let Err(e) = remote_sas.confirm().await {
-                warn!("Failed to tell remote about successful verification: {}", e);
+            match candidate {
+                Some((id, pending)) => {
+                    if reply.send(describe(id, &pending.sas)).is_ok() {
+                        pending.shown = true;
+                    }
+                }
+                None => self.waiting.push((wanted, reply)),
             }
-        } else {
-            warn!(
-                "Verification failed. Local: '{}'. Remote: '{}'",
-                local_sas.input, remote
-            );
-            if let Err(e) = remote_sas.cancel().await {
-                warn!("Failed to tell remote about failed verification: {}", e);
+        }
+    }
+
+    async fn conclude(sas: RemoteSas, confirmed: bool) {
+        if confirmed {
+            info!("Verification confirmed locally");
+            if let Err(e) = sas.confirm().await {
+                warn!("Failed to tell remote about successful verification: {}", e);
             }
+            return;
+        }
+
+        warn!(
+            "Cancelling verification with {}",
+            sas.other_device().device_id()
+        );
+        if let Err(e) = sas.cancel().await {
+            warn!("Failed to tell remote about failed verification: {}", e);
         }
     }
 }
 
+fn describe(id: &str, sas: &RemoteSas) -> LocalVerification {
+    LocalVerification {
+        id: id.to_string(),
+        user_id: sas.other_device().user_id().to_string(),
+        device_id: sas.other_device().device_id().to_string(),
+        emoji: sas
+            .emoji()
+            .map(|emoji| {
+                emoji
+                    .iter()
+                    .map(|(symbol, description)| Emoji {
+                        symbol: symbol.to_string(),
+                        description: description.to_string(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        decimals: sas.decimals(),
+    }
+}
+
 #[derive(Deserialize)]
 struct WellKnown {
     #[serde(rename = "m.homeserver")]
@@ -481,10 +2113,40 @@ struct Server {
     base_url: String,
 }
 
-pub fn resolve_well_known(matrix_id: &str) -> Result<String, Error> {
-    let url = format!("https://{}/.well-known/matrix/client", matrix_id);
+/// Look up the homeserver of `server`, retrying on failures as the network
+/// might not be fully up yet during boot
+async fn discover_homeserver(server: &str) -> Result<Url, Error> {
+    let mut backoff = Backoff::new();
+    let mut attempt = 1;
+
+    let homeserver_url = loop {
+        match resolve_well_known(server).await {
+            Ok(v) => break v,
+            Err(e) if attempt < WELL_KNOWN_ATTEMPTS => {
+                warn!("Failed to resolve homeserver of {}: {}", server, e);
+                sleep(Duration::from_secs(backoff.get_backoff())).await;
+                backoff.backoff();
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    debug!("Homeserver of {} is {}", server, homeserver_url);
+    Url::parse(&homeserver_url).map_err(|_| Error::InvalidHomeServer)
+}
+
+/// Servers without a well-known entry are assumed to host the homeserver
+/// themselves
+async fn resolve_well_known(server: &str) -> Result<String, Error> {
+    let url = format!("https://{}/.well-known/matrix/client", server);
+
+    let response = reqwest::get(url).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(format!("https://{}", server));
+    }
 
-    let response = reqwest::blocking::get(url)?.json::<WellKnown>()?;
+    let response = response.error_for_status()?.json::<WellKnown>().await?;
 
     Ok(response.homeserver_url.base_url)
 }
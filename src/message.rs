@@ -23,16 +23,59 @@ use serde_derive::Serialize;
 use chrono::DateTime;
 use chrono::Local;
 
+use tera::Context;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum Packet {
     Message(Message),
 
-    Sas(Sas),
+    Verify(VerifyCommand),
 }
 
+/// Requests of `alert --verify`, each answered by the daemon with one line
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Sas {
-    pub input: String,
+pub enum VerifyCommand {
+    /// Show the verification with this ID or the next one not shown yet.
+    /// Answered with a `Verification`, which expects a `Confirmation`.
+    Show(Option<String>),
+
+    /// Answered with all pending `Verification`s
+    List,
+
+    /// Answered with whether the verification was pending
+    Cancel(String),
+}
+
+/// A verification of the daemon's device as shown to `alert --verify`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Verification {
+    /// The transaction ID of the verification
+    pub id: String,
+
+    pub user_id: String,
+
+    pub device_id: String,
+
+    #[serde(default)]
+    pub emoji: Vec<Emoji>,
+
+    pub decimals: Option<(u16, u16, u16)>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Emoji {
+    pub symbol: String,
+
+    pub description: String,
+}
+
+/// Whether the user confirmed that both devices show the same
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Confirmation {
+    /// The transaction ID of the verification answered
+    pub id: String,
+
+    pub confirmed: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -54,6 +97,82 @@ pub struct Message {
     pub timestamp: DateTime<Local>,
 
     pub version: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+
+    /// Messages with the same key update the first one instead of being
+    /// posted anew
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
+    /// Messages with the same thread are grouped below a daily root message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+
+    /// Users to notify in addition to those configured for the level
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub name: String,
+
+    pub content_type: String,
+
+    #[serde(with = "base64_serde")]
+    pub content: Vec<u8>,
+
+    /// Whether only the tail of the original file is contained
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+impl Attachment {
+    /// Keep at most the last `max_size` bytes, as the end of a log is what
+    /// usually explains a failure
+    pub fn truncate(&mut self, max_size: usize) {
+        if self.content.len() > max_size {
+            self.content.drain(..self.content.len() - max_size);
+            self.truncated = true;
+        }
+    }
+
+    /// The content as text for backends which cannot take files
+    pub fn as_text(&self, max_size: usize) -> String {
+        let start = self.content.len().saturating_sub(max_size);
+        let text = String::from_utf8_lossy(&self.content[start..]);
+        if start > 0 || self.truncated {
+            format!("[...]{}", text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+impl Message {
+    /// The variables available to user-supplied message templates
+    pub fn template_context(&self) -> Context {
+        let mut context = Context::default();
+        context.insert("m", self);
+        context.insert("level_color", &String::from(self.level.clone()));
+        context
+    }
+
+    /// Append the attachments to the text, for backends which cannot take
+    /// files. Each attachment is cut down to its last `max_size` bytes.
+    pub fn inline_attachments(&self, max_size: usize) -> Message {
+        let mut message = self.clone();
+        for attachment in message.attachments.drain(..) {
+            message.text.push_str(&format!(
+                "\n\n{}:\n```\n{}\n```",
+                attachment.name,
+                attachment.as_text(max_size).trim_end()
+            ));
+        }
+        message
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -69,6 +188,17 @@ pub enum Level {
     Unknown,
 }
 
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Ok => "OK",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+            Level::Unknown => "UNKNOWN",
+        }
+    }
+}
+
 impl From<Level> for String {
     fn from(v: Level) -> Self {
         match v {
@@ -101,3 +231,22 @@ mod chrono_serde {
         Ok(Local.timestamp(secs, 0))
     }
 }
+
+mod base64_serde {
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&base64::encode(content))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
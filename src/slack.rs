@@ -15,14 +15,36 @@
  *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
+use crate::config::Mentions;
+use crate::config::RateLimit;
+use crate::key_store;
+use crate::key_store::KeyStore;
+use crate::key_store::Sent;
+use crate::message::Attachment as FileAttachment;
 use crate::message::Message;
+use crate::spool_dispatcher::Report;
+use crate::token_bucket::TokenBucket;
 
 use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::RETRY_AFTER;
+use reqwest::Response;
+
+use serde_json::json;
+use serde_json::Value;
+
+use tera::Tera;
 
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
 
 use log::debug;
+use log::error;
 use log::warn;
 
 use serde_derive::Deserialize;
@@ -94,12 +116,94 @@ fn transform_fields(fields: &BTreeMap<String, String>) -> Vec<Field> {
         .collect()
 }
 
+/// Slack error codes on which retrying the same message cannot succeed
+const PERMANENT_ERRORS: &[&str] = &[
+    "channel_not_found",
+    "invalid_arguments",
+    "invalid_attachments",
+    "invalid_blocks",
+    "is_archived",
+    "msg_too_long",
+    "no_text",
+    "not_in_channel",
+    "restricted_action",
+    "too_many_attachments",
+];
+
+/// Slack error codes on which an earlier message is posted anew instead of
+/// being updated
+const UNUPDATABLE_ERRORS: &[&str] = &[
+    "cant_update_message",
+    "edit_window_closed",
+    "message_not_found",
+];
+
+const POST_MESSAGE_URL: &str = "https://slack.com/api/chat.postMessage";
+
+const UPDATE_MESSAGE_URL: &str = "https://slack.com/api/chat.update";
+
+const GET_UPLOAD_URL_URL: &str = "https://slack.com/api/files.getUploadURLExternal";
+
+const COMPLETE_UPLOAD_URL: &str = "https://slack.com/api/files.completeUploadExternal";
+
+const TEMPLATE_NAME: &str = "slack";
+
+const MAX_BLOCKS: usize = 50;
+
+pub enum Endpoint {
+    Webhook(String),
+
+    Api { token: String, channel: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    ok: bool,
+
+    error: Option<String>,
+
+    channel: Option<String>,
+
+    ts: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadUrlResponse {
+    ok: bool,
+
+    error: Option<String>,
+
+    upload_url: Option<String>,
+
+    file_id: Option<String>,
+}
+
+/// The location of a message posted via the Web API
+#[derive(Debug, Clone)]
+struct Posted {
+    channel: String,
+
+    ts: String,
+}
+
 pub struct Slack {
-    webhook_url: String,
+    endpoint: Endpoint,
+
+    tera: Option<Tera>,
+
+    rate_limiter: TokenBucket,
+
+    inline_size: usize,
+
+    /// Only set for the Web API, as webhooks cannot update messages or
+    /// reply in threads
+    key_store: Option<KeyStore>,
+
+    mentions: Mentions,
 
     spooler: Receiver<Message>,
 
-    send_reporter: Sender<Option<Message>>,
+    send_reporter: Sender<Report>,
 
     terminator: tokio::sync::broadcast::Receiver<()>,
 }
@@ -109,21 +213,101 @@ pub enum Error {
     ReqwestError(reqwest::Error),
 
     StatusCode(u16),
+
+    RateLimited(Duration),
+
+    Api(String),
+
+    Json(serde_json::Error),
+
+    Template(tera::Error),
+
+    InvalidBlocks(&'static str),
+}
+
+impl Error {
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::Api(code) => PERMANENT_ERRORS.contains(&code.as_str()),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::ReqwestError(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<tera::Error> for Error {
+    fn from(e: tera::Error) -> Self {
+        Error::Template(e)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ReqwestError(e) => write!(f, "{}", e),
+            Error::StatusCode(code) => write!(f, "slack replied with status {}", code),
+            Error::RateLimited(delay) => {
+                write!(f, "slack asked to retry after {}s", delay.as_secs())
+            }
+            Error::Api(code) => write!(f, "slack replied with error '{}'", code),
+            Error::Json(e) => write!(f, "invalid JSON: {}", e),
+            Error::Template(e) => write!(f, "message template is invalid: {:#?}", e),
+            Error::InvalidBlocks(reason) => write!(f, "invalid Block Kit message: {}", reason),
+        }
+    }
 }
 
 impl Slack {
     pub fn new(
         spooler: Receiver<Message>,
-        send_reporter: Sender<Option<Message>>,
-        webhook_url: String,
+        send_reporter: Sender<Report>,
+        endpoint: Endpoint,
+        message_template: Option<&str>,
+        rate_limit: &RateLimit,
+        inline_size: usize,
         terminator: tokio::sync::broadcast::Receiver<()>,
-    ) -> Self {
-        Slack {
-            webhook_url,
+    ) -> Result<Self, Error> {
+        let tera = match message_template {
+            None => None,
+            Some(message_template) => {
+                let mut tera = Tera::default();
+                tera.add_raw_template(TEMPLATE_NAME, message_template)?;
+                Some(tera)
+            }
+        };
+
+        Ok(Slack {
+            endpoint,
+            tera,
+            rate_limiter: TokenBucket::new(rate_limit.per_minute, rate_limit.burst),
+            inline_size,
+            key_store: None,
+            mentions: Mentions::default(),
             spooler,
             send_reporter,
             terminator,
-        }
+        })
+    }
+
+    pub fn with_key_store(mut self, key_store: KeyStore) -> Self {
+        self.key_store = Some(key_store);
+        self
+    }
+
+    pub fn with_mentions(mut self, mentions: Mentions) -> Self {
+        self.mentions = mentions;
+        self
     }
 
     pub async fn send_messages(&mut self) {
@@ -131,13 +315,32 @@ impl Slack {
             tokio::select! {
                 next = self.spooler.recv() => {
                    if let Some(message) = next {
-                        debug!("Sending message");
-                        if self.send_message(&message).await.is_err() {
-                            if self.send_reporter.send(Some(message)).await.is_err() {
-                                debug!("Slack shutting down because send_reporter is down after trying to report error");
+                        tokio::select! {
+                            _ = self.rate_limiter.acquire() => {}
+                            _ = self.terminator.recv() => {
+                                debug!("Slack shutting down on termination signal");
                                 return;
                             }
-                        } else if self.send_reporter.send(None).await.is_err() {
+                        }
+
+                        debug!("Sending message");
+                        let report = match self.send_message(&message).await {
+                            Err(Error::RateLimited(delay)) => {
+                                warn!("Slack rate limit hit, retrying after {}s", delay.as_secs());
+                                self.rate_limiter.pause_until(Instant::now() + delay);
+                                Report::RetryAfter(message, delay)
+                            }
+                            Err(e) if e.is_permanent() => {
+                                error!("Dropping message as it cannot be sent: {}", e);
+                                Report::Sent
+                            }
+                            Err(e) => {
+                                warn!("Error while sending: {}", e);
+                                Report::Failed(message)
+                            }
+                            Ok(()) => Report::Sent,
+                        };
+                        if self.send_reporter.send(report).await.is_err() {
                             debug!("Slack shutting down because send_reporter is down");
                             return;
                         }
@@ -154,29 +357,348 @@ impl Slack {
         }
     }
 
-    async fn send_message(&self, message: &Message) -> Result<(), ()> {
-        let backend_message = BackendMessage::from(message);
+    /// Send the message. The channel and timestamp returned by the Web API
+    /// are recorded for messages with a key or thread, the only ones later
+    /// messages refer to.
+    async fn send_message(&mut self, message: &Message) -> Result<(), Error> {
+        let token = match &self.endpoint {
+            Endpoint::Webhook(webhook_url) => {
+                let payload =
+                    self.channel_payload(&message.inline_attachments(self.inline_size))?;
+                self.send_to_webhook(webhook_url, &payload).await?;
+                return Ok(());
+            }
+            Endpoint::Api { token, .. } => token.clone(),
+        };
+
+        let mut payload = self.channel_payload(message)?;
+        let channel = payload["channel"].as_str().unwrap_or_default().to_string();
+
+        // A message addressed to another channel than the earlier one is
+        // posted anew there
+        let earlier = message.key.as_deref().and_then(|key| {
+            let sent = self.key_store.as_ref()?.get(key)?;
+            sent.is_in(&channel).then(|| (key, sent.clone()))
+        });
+
+        let updated = match earlier {
+            Some((key, mut sent)) => {
+                let mut payload = self.build_payload(&sent.with_history(message))?;
+                payload["channel"] = sent.room.to_string().into();
+                payload["ts"] = sent.id.to_string().into();
+                match self
+                    .call_chat_api(UPDATE_MESSAGE_URL, &token, &payload)
+                    .await
+                {
+                    Err(Error::Api(code)) if UNUPDATABLE_ERRORS.contains(&code.as_str()) => {
+                        warn!("Posting '{}' anew as it cannot be updated: {}", key, code);
+                        None
+                    }
+                    Err(e) => return Err(e),
+                    Ok(posted) => {
+                        debug!("Updated message with key '{}'", key);
+                        sent.push(message);
+                        self.remember(key, sent).await;
+                        Some(posted)
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let posted = match updated {
+            Some(v) => v,
+            None => {
+                if let Some(thread) = &message.thread {
+                    let root = self.thread_root(&token, &channel, thread, message).await?;
+                    payload["thread_ts"] = root.into();
+                }
+                let posted = self
+                    .call_chat_api(POST_MESSAGE_URL, &token, &payload)
+                    .await?;
+                if let Some(key) = &message.key {
+                    let mut sent = Sent::new(&posted.channel, &posted.ts, message);
+                    sent.addressed = Some(channel).filter(|channel| *channel != posted.channel);
+                    self.remember(key, sent).await;
+                }
+                posted
+            }
+        };
+
+        for attachment in &message.attachments {
+            self.rate_limiter.acquire().await;
+            match self.upload_file(&token, &posted, attachment).await {
+                Err(Error::RateLimited(delay)) => {
+                    warn!(
+                        "Slack rate limit hit, dropping attachment '{}'",
+                        attachment.name
+                    );
+                    self.rate_limiter.pause_until(Instant::now() + delay);
+                }
+                Err(e) => warn!("Failed to upload attachment '{}': {}", attachment.name, e),
+                Ok(()) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The payload addressed to the channel of the message or the default
+    /// one, notifying the mentioned users
+    fn channel_payload(&self, message: &Message) -> Result<Value, Error> {
+        let mut payload = self.build_payload(message)?;
+        mention(&mut payload, &self.mentions.for_message(message));
+
+        let default_channel = match &self.endpoint {
+            Endpoint::Webhook(_) => None,
+            Endpoint::Api { channel, .. } => Some(channel),
+        };
+        if payload.get("channel").map_or(true, Value::is_null) {
+            if let Some(channel) = message.channel.as_ref().or(default_channel) {
+                payload["channel"] = channel.to_string().into();
+            }
+        }
+
+        Ok(payload)
+    }
+
+    /// The timestamp of today's thread root, which is posted on first use
+    async fn thread_root(
+        &mut self,
+        token: &str,
+        channel: &str,
+        thread: &str,
+        message: &Message,
+    ) -> Result<String, Error> {
+        if let Some(sent) = self
+            .key_store
+            .as_ref()
+            .and_then(|v| v.thread(channel, thread))
+        {
+            return Ok(sent.id.to_string());
+        }
+
+        debug!("Starting thread '{}' in {}", thread, channel);
+        self.rate_limiter.acquire().await;
+        let payload = json!({
+            "channel": channel,
+            "text": key_store::thread_title(thread),
+            "username": crate::util::hostname(),
+        });
+        let root = self
+            .call_chat_api(POST_MESSAGE_URL, token, &payload)
+            .await?;
+        if let Some(key_store) = &mut self.key_store {
+            key_store
+                .insert_thread(channel, thread, Sent::new(&root.channel, &root.ts, message))
+                .await;
+        }
+        Ok(root.ts)
+    }
+
+    async fn remember(&mut self, key: &str, sent: Sent) {
+        if let Some(key_store) = &mut self.key_store {
+            key_store.insert(key, sent).await;
+        }
+    }
+
+    /// Render the message template if there is one, falling back to legacy
+    /// attachments if its output is not a valid Block Kit message
+    fn build_payload(&self, message: &Message) -> Result<Value, Error> {
+        if let Some(tera) = &self.tera {
+            match render(tera, message) {
+                Ok(payload) => return Ok(payload),
+                Err(e) => warn!("Falling back to attachments: {}", e),
+            }
+        }
+
+        Ok(serde_json::to_value(BackendMessage::from(message))?)
+    }
+
+    async fn send_to_webhook(&self, webhook_url: &str, payload: &Value) -> Result<(), Error> {
+        let client = reqwest::Client::builder().build()?;
+
+        let response = client.post(webhook_url).json(payload).send().await?;
+
+        check_status(response).map(|_| ())
+    }
 
-        let client = reqwest::Client::builder().build().map_err(|_| ())?;
+    /// Post or update a message, depending on `url`
+    async fn call_chat_api(
+        &self,
+        url: &str,
+        token: &str,
+        payload: &Value,
+    ) -> Result<Posted, Error> {
+        let client = reqwest::Client::builder().build()?;
 
         let response = client
-            .post(&self.webhook_url)
-            .json(&backend_message)
+            .post(url)
+            .bearer_auth(token)
+            .json(payload)
             .send()
-            .await;
+            .await?;
 
+        let response = check_status(response)?.json::<ApiResponse>().await?;
         match response {
-            Ok(r) => match r.status().as_u16() {
-                200 => Ok(()),
-                _ => {
-                    warn!("Upstream reported error: {:#?}", r);
-                    Err(())
-                }
-            },
-            Err(e) => {
-                warn!("Error while sending: {}", e);
-                Err(())
+            ApiResponse {
+                ok: true,
+                channel: Some(channel),
+                ts: Some(ts),
+                ..
+            } => {
+                debug!("Slack stored message {} in {}", ts, channel);
+                Ok(Posted { channel, ts })
             }
+            ApiResponse { error, .. } => Err(Error::Api(error.unwrap_or_default())),
+        }
+    }
+
+    /// Upload a file into the thread of a posted message. Failures are not
+    /// retried as that would post the message again.
+    async fn upload_file(
+        &self,
+        token: &str,
+        posted: &Posted,
+        attachment: &FileAttachment,
+    ) -> Result<(), Error> {
+        let client = reqwest::Client::builder().build()?;
+
+        let length = attachment.content.len().to_string();
+        let response = client
+            .post(GET_UPLOAD_URL_URL)
+            .bearer_auth(token)
+            .form(&[
+                ("filename", attachment.name.as_str()),
+                ("length", length.as_str()),
+            ])
+            .send()
+            .await?;
+
+        let response = check_status(response)?.json::<UploadUrlResponse>().await?;
+        let (upload_url, file_id) = match response {
+            UploadUrlResponse {
+                ok: true,
+                upload_url: Some(upload_url),
+                file_id: Some(file_id),
+                ..
+            } => (upload_url, file_id),
+            UploadUrlResponse { error, .. } => return Err(Error::Api(error.unwrap_or_default())),
+        };
+
+        let response = client
+            .post(upload_url)
+            .header(CONTENT_TYPE, &attachment.content_type)
+            .body(attachment.content.clone())
+            .send()
+            .await?;
+        check_status(response)?;
+
+        let response = client
+            .post(COMPLETE_UPLOAD_URL)
+            .bearer_auth(token)
+            .json(&json!({
+                "files": [{ "id": file_id, "title": attachment.name }],
+                "channel_id": posted.channel,
+                "thread_ts": posted.ts,
+            }))
+            .send()
+            .await?;
+
+        match check_status(response)?.json::<ApiResponse>().await? {
+            ApiResponse { ok: true, .. } => Ok(()),
+            ApiResponse { error, .. } => Err(Error::Api(error.unwrap_or_default())),
+        }
+    }
+}
+
+fn check_status(response: Response) -> Result<Response, Error> {
+    match response.status().as_u16() {
+        200 => Ok(response),
+        429 => {
+            let delay = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(1);
+            Err(Error::RateLimited(Duration::from_secs(delay)))
+        }
+        code => {
+            warn!("Upstream reported error: {:#?}", response);
+            Err(Error::StatusCode(code))
         }
     }
 }
+
+/// Prefix the text of `payload` with the mentions, and the blocks if any as
+/// Slack only shows the text in notifications then
+fn mention(payload: &mut Value, mentions: &[String]) {
+    if mentions.is_empty() {
+        return;
+    }
+
+    let mentions = mentions
+        .iter()
+        .map(|mention| match mention.as_str() {
+            "@room" | "@channel" => "<!channel>".to_string(),
+            "@here" => "<!here>".to_string(),
+            user => format!("<@{}>", user.trim_start_matches('@')),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    payload["text"] = match payload.get("text").and_then(Value::as_str) {
+        Some(text) => format!("{} {}", mentions, text).into(),
+        None => mentions.to_string().into(),
+    };
+
+    if let Some(blocks) = payload.get_mut("blocks").and_then(Value::as_array_mut) {
+        if blocks.len() < MAX_BLOCKS {
+            blocks.insert(
+                0,
+                json!({
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": mentions },
+                }),
+            );
+        }
+    }
+}
+
+fn render(tera: &Tera, message: &Message) -> Result<Value, Error> {
+    let rendered = tera.render(TEMPLATE_NAME, &message.template_context())?;
+
+    let mut payload = match serde_json::from_str(&rendered)? {
+        Value::Array(blocks) => json!({ "blocks": blocks }),
+        payload @ Value::Object(_) => payload,
+        _ => {
+            return Err(Error::InvalidBlocks(
+                "expected an object or an array of blocks",
+            ))
+        }
+    };
+
+    let blocks = payload
+        .get("blocks")
+        .and_then(Value::as_array)
+        .ok_or(Error::InvalidBlocks("missing array 'blocks'"))?;
+    if blocks.is_empty() || blocks.len() > MAX_BLOCKS {
+        return Err(Error::InvalidBlocks("expected between 1 and 50 blocks"));
+    }
+    if blocks
+        .iter()
+        .any(|block| block.get("type").and_then(Value::as_str).is_none())
+    {
+        return Err(Error::InvalidBlocks("every block needs a 'type'"));
+    }
+
+    if payload.get("text").is_none() {
+        payload["text"] = message.title.to_string().into();
+    }
+    if payload.get("username").is_none() {
+        payload["username"] = crate::util::hostname().into();
+    }
+
+    Ok(payload)
+}
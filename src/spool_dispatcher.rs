@@ -26,18 +26,50 @@ use log::debug;
 use tokio::select;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio::time::interval;
+use tokio::time::Instant;
 use tokio::time::Interval;
 
+/// The outcome of a transmission as reported by a backend
+pub enum Report {
+    Sent,
+
+    Failed(Message),
+
+    /// The backend asked to retry not before the given duration elapsed
+    RetryAfter(Message, Duration),
+}
+
+/// Requests from backends operating the daemon, e.g. via chat commands
+pub enum Control {
+    Status(oneshot::Sender<Status>),
+
+    /// Send all spooled messages right away
+    Flush,
+}
+
+pub struct Status {
+    pub queued: usize,
+
+    pub backoff: Duration,
+}
+
 pub struct SpoolDispatcher {
     spooler: Spooler,
 
     sender: Sender<Message>,
 
-    receiver: Receiver<Option<Message>>,
+    receiver: Receiver<Report>,
 
     backoff: Backoff,
 
+    retry_at: Option<Instant>,
+
+    flushing: bool,
+
+    control: Receiver<Control>,
+
     terminator: tokio::sync::broadcast::Receiver<()>,
 }
 
@@ -45,7 +77,8 @@ impl SpoolDispatcher {
     pub fn new(
         spooler: Spooler,
         sender: Sender<Message>,
-        receiver: Receiver<Option<Message>>,
+        receiver: Receiver<Report>,
+        control: Receiver<Control>,
         terminator: tokio::sync::broadcast::Receiver<()>,
     ) -> Self {
         SpoolDispatcher {
@@ -53,6 +86,9 @@ impl SpoolDispatcher {
             sender,
             receiver,
             backoff: Backoff::new(),
+            retry_at: None,
+            flushing: false,
+            control,
             terminator,
         }
     }
@@ -65,6 +101,7 @@ impl SpoolDispatcher {
 
             select! {
                 _ = ticker.tick() => {
+                    self.retry_at = None;
                     if let Some(message) = self.spooler.pop_message() {
                         if let (Err(_),_) = tokio::join!(
                             self.sender.send(message),
@@ -77,12 +114,20 @@ impl SpoolDispatcher {
                 }
                 work = self.receiver.recv() => {
                     match work {
-                        Some(Some(message)) => {
+                        Some(Report::Failed(message)) => {
                             self.spooler.queue(message);
                             self.spooler.store().await;
                             self.backoff.backoff();
+                            self.flushing = false;
+                        }
+                        Some(Report::RetryAfter(message, delay)) => {
+                            debug!("Retrying after {}ms as requested", delay.as_millis());
+                            self.spooler.queue_front(message);
+                            self.spooler.store().await;
+                            self.retry_at = Some(Instant::now() + delay);
+                            self.flushing = false;
                         }
-                        Some(None) => {
+                        Some(Report::Sent) => {
                             self.backoff.reset();
                         }
                         None => {
@@ -91,6 +136,9 @@ impl SpoolDispatcher {
                         }
                     }
                 }
+                Some(control) = self.control.recv() => {
+                    self.handle_control(control);
+                }
                 _ = self.terminator.recv() => {
                     debug!("Spool dispatcher shutting down on termination signal");
                     return;
@@ -99,16 +147,46 @@ impl SpoolDispatcher {
         }
     }
 
-    async fn setup_ticker(&self) -> Interval {
-        let seconds = if self.spooler.is_empty() {
-            86400
+    fn handle_control(&mut self, control: Control) {
+        match control {
+            Control::Status(reply) => {
+                let status = Status {
+                    queued: self.spooler.len(),
+                    backoff: Duration::from_secs(self.backoff.get_backoff()),
+                };
+                if reply.send(status).is_err() {
+                    debug!("Status requester went away");
+                }
+            }
+            Control::Flush => {
+                debug!("Flushing {} spooled messages", self.spooler.len());
+                self.backoff.reset();
+                self.retry_at = None;
+                self.flushing = true;
+            }
+        }
+    }
+
+    async fn setup_ticker(&mut self) -> Interval {
+        if self.spooler.is_empty() {
+            self.flushing = false;
+        }
+
+        let period = if self.spooler.is_empty() {
+            Duration::from_secs(86400)
+        } else if self.flushing {
+            Duration::from_millis(1)
+        } else if let Some(retry_at) = self.retry_at {
+            retry_at
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_millis(1))
         } else {
-            self.backoff.get_backoff()
+            Duration::from_secs(self.backoff.get_backoff())
         };
 
-        debug!("ticker at {}s", seconds);
+        debug!("ticker at {}ms", period.as_millis());
 
-        let mut ticker = interval(Duration::from_secs(seconds));
+        let mut ticker = interval(period);
         ticker.tick().await;
         ticker
     }
@@ -48,6 +48,10 @@ impl Spooler {
         self.queue.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
     pub fn queue(&mut self, m: Message) {
         debug!("Queueing message");
         self.queue.push(m);
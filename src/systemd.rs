@@ -39,6 +39,18 @@ pub async fn notify_about_start() {
     }
 }
 
+/// Show a human-readable state in `systemctl status`
+pub async fn notify_status(status: String) {
+    let result = spawn_blocking(move || {
+        notify_systemd(&[("STATUS", &status)]);
+    })
+    .await;
+
+    if let Err(e) = result {
+        warn!("watchdog failed to notify about status: {}", e);
+    }
+}
+
 pub async fn notify_about_termination() {
     let result = spawn_blocking(|| {
         notify_systemd(&[("STOPPING", "1")]);
@@ -0,0 +1,84 @@
+/*  alerter: Alerter to chat servers
+ *  Copyright (C) 2019 The alerter developers
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tokio::time::sleep_until;
+use tokio::time::Instant;
+
+use log::debug;
+
+/// Paces transmissions to `per_minute` on average while allowing bursts of
+/// `burst` messages
+pub struct TokenBucket {
+    capacity: f64,
+
+    tokens: f64,
+
+    per_second: f64,
+
+    last_refill: Instant,
+
+    paused_until: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        TokenBucket {
+            capacity,
+            tokens: capacity,
+            per_second: f64::from(per_minute.max(1)) / 60.0,
+            last_refill: Instant::now(),
+            paused_until: None,
+        }
+    }
+
+    /// Hand out no tokens before `until`, e.g. when upstream asked to slow
+    /// down
+    pub fn pause_until(&mut self, until: Instant) {
+        self.tokens = 0.0;
+        self.paused_until = Some(self.paused_until.map_or(until, |v| v.max(until)));
+    }
+
+    pub async fn acquire(&mut self) {
+        if let Some(until) = self.paused_until.take() {
+            debug!("Rate limit pauses sending");
+            sleep_until(until).await;
+            self.last_refill = Instant::now();
+        }
+
+        self.refill();
+        if self.tokens < 1.0 {
+            sleep(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+            .await;
+            self.refill();
+        }
+
+        self.tokens -= 1.0;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+}
@@ -22,3 +22,17 @@ pub fn hostname() -> String {
         .map(|v| v.trim().to_string())
         .unwrap_or_else(|_| "".to_string())
 }
+
+/// Loki and Prometheus label names must match `[a-zA-Z_][a-zA-Z0-9_]*`
+pub fn sanitise_label_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
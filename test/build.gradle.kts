@@ -35,6 +35,8 @@ tasks.withType<JavaCompile> {
 tasks.withType<Test> {
     useJUnitPlatform()
     systemProperty("de.njsm.alerter.config", "$projectDir/src/test/resources/config.yml")
+    systemProperty("de.njsm.alerter.loki.config", "$projectDir/src/test/resources/loki_config.yml")
+    systemProperty("de.njsm.alerter.alertmanager.config", "$projectDir/src/test/resources/alertmanager_config.yml")
     systemProperty("de.njsm.alerter.client.config", "$projectDir/src/test/resources/client_config.yml")
     systemProperty("de.njsm.alerter.logconfig", "$projectDir/src/test/resources/log4rs.yml")
     systemProperty("de.njsm.alerter.client.binary", "$projectDir/../target/debug/alert")
@@ -27,6 +27,10 @@ public class Config {
 
     public static final String CONFIG_PATH = "de.njsm.alerter.config";
 
+    public static final String LOKI_CONFIG_PATH = "de.njsm.alerter.loki.config";
+
+    public static final String ALERTMANAGER_CONFIG_PATH = "de.njsm.alerter.alertmanager.config";
+
     public static final String CLIENT_CONFIG_PATH = "de.njsm.alerter.client.config";
 
     public static final String LOG_CONFIG_PATH = "de.njsm.alerter.logconfig";
@@ -45,6 +49,14 @@ public class Config {
         return System.getProperty(CONFIG_PATH);
     }
 
+    public static String getLokiConfigPath() {
+        return System.getProperty(LOKI_CONFIG_PATH);
+    }
+
+    public static String getAlertmanagerConfigPath() {
+        return System.getProperty(ALERTMANAGER_CONFIG_PATH);
+    }
+
     public static String getClientConfigPath() {
         return System.getProperty(CLIENT_CONFIG_PATH);
     }
@@ -20,6 +20,7 @@
 package de.njsm.alerter.test;
 
 import de.njsm.alerter.test.client.Alert;
+import de.njsm.alerter.test.server.Alerter;
 import org.apache.commons.io.IOUtils;
 import org.junit.jupiter.api.AfterEach;
 import org.junit.jupiter.api.BeforeEach;
@@ -37,7 +38,6 @@ import org.newsclub.net.unix.AFUNIXSocketAddress;
 
 import java.io.*;
 import java.nio.charset.Charset;
-import java.util.concurrent.atomic.AtomicBoolean;
 
 import static de.njsm.alerter.test.Config.SOCKET_PATH;
 import static org.junit.jupiter.api.Assertions.assertEquals;
@@ -49,13 +49,7 @@ public class ServerTest {
 
     private static final String QUEUE_PATH = "src/test/resources/queue";
 
-    private Process alerter;
-
-    private Thread logDispatcherOut;
-
-    private Thread logDispatcherErr;
-
-    private AtomicBoolean keepRunning;
+    private Alerter alerter;
 
     @BeforeEach
     public void setup(MockServerClient client) throws IOException {
@@ -63,45 +57,12 @@ public class ServerTest {
         new File(QUEUE_PATH).delete();
         new File(SOCKET_PATH).delete();
 
-        alerter = Runtime.getRuntime().exec(new String[]{
-                Config.getServerBinaryPath(),
-                "-c",
-                Config.getConfigPath(),
-                "-v",
-                Config.getLogConfigPath()
-        });
-
-        keepRunning = new AtomicBoolean(true);
-
-        logDispatcherOut = new Thread(() -> {
-            while (keepRunning.get()) {
-                try {
-                    alerter.getInputStream().transferTo(System.out);
-                } catch (IOException e) {
-                    e.printStackTrace();
-                }
-            }
-        });
-        logDispatcherOut.start();
-
-        logDispatcherErr = new Thread(() -> {
-            while (keepRunning.get()) {
-                try {
-                    alerter.getErrorStream().transferTo(System.err);
-                } catch (IOException e) {
-                    e.printStackTrace();
-                }
-            }
-        });
-        logDispatcherErr.start();
+        alerter = Alerter.start(Config.getConfigPath());
     }
 
     @AfterEach
     void tearDown() {
-        keepRunning.set(false);
-        logDispatcherOut.interrupt();
-        logDispatcherErr.interrupt();
-        alerter.destroy();
+        alerter.stop();
     }
 
     @Test
@@ -140,6 +101,37 @@ public class ServerTest {
         assertEmptyQueue();
     }
 
+    @Test
+    public void rateLimitedTransmissionIsRetriedAfterDelay(MockServerClient client) throws Exception {
+        client.when(request()
+                        .withMethod("POST"),
+                Times.once()
+        ).respond(
+                HttpResponse.response()
+                        .withStatusCode(429)
+                        .withHeader("Retry-After", "3")
+        );
+        prepareOkReply(client);
+
+        Alert.build()
+                .withText("simple")
+                .call();
+
+        Thread.sleep(2000);
+        client.verify(
+                request().withPath("/slack"),
+                VerificationTimes.exactly(1)
+        );
+
+        Thread.sleep(3000);
+        client.verify(
+                request().withPath("/slack"),
+                VerificationTimes.exactly(2)
+        );
+
+        assertEmptyQueue();
+    }
+
     @Test
     void illegalMessageIsIgnored(MockServerClient client) throws Exception {
         File socketFile = new File(SOCKET_PATH);
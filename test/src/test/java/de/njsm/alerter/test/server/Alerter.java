@@ -0,0 +1,80 @@
+/* alerter: Alerter to chat servers
+ * Copyright (C) 2019  The alerter developers
+ *
+ * This file is part of the alerter program suite.
+ *
+ * alerter is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * alerter is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+package de.njsm.alerter.test.server;
+
+import de.njsm.alerter.test.Config;
+
+import java.io.IOException;
+import java.util.concurrent.atomic.AtomicBoolean;
+
+public class Alerter {
+
+    private final Process alerter;
+
+    private final Thread logDispatcherOut;
+
+    private final Thread logDispatcherErr;
+
+    private final AtomicBoolean keepRunning;
+
+    private Alerter(Process alerter) {
+        this.alerter = alerter;
+        keepRunning = new AtomicBoolean(true);
+
+        logDispatcherOut = new Thread(() -> {
+            while (keepRunning.get()) {
+                try {
+                    alerter.getInputStream().transferTo(System.out);
+                } catch (IOException e) {
+                    e.printStackTrace();
+                }
+            }
+        });
+        logDispatcherOut.start();
+
+        logDispatcherErr = new Thread(() -> {
+            while (keepRunning.get()) {
+                try {
+                    alerter.getErrorStream().transferTo(System.err);
+                } catch (IOException e) {
+                    e.printStackTrace();
+                }
+            }
+        });
+        logDispatcherErr.start();
+    }
+
+    public static Alerter start(String configPath) throws IOException {
+        return new Alerter(Runtime.getRuntime().exec(new String[]{
+                Config.getServerBinaryPath(),
+                "-c",
+                configPath,
+                "-v",
+                Config.getLogConfigPath()
+        }));
+    }
+
+    public void stop() {
+        keepRunning.set(false);
+        logDispatcherOut.interrupt();
+        logDispatcherErr.interrupt();
+        alerter.destroy();
+    }
+}